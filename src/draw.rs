@@ -0,0 +1,280 @@
+//
+// draw --- vector rendering of box drawing characters, block
+// elements and Braille patterns.
+//
+// lines and blocks are drawn by \rule to the edges of the cell, i.e.,
+// csize.width by lheight, so that those of adjacent cells join.
+//
+
+use crate::{Param, Style, fg_cmd};
+
+/// arms of U+2500..U+257F; 2 bits each of left, up, right and down
+/// from the lowest.  0 none, 1 light, 2 heavy and 3 double.
+#[rustfmt::skip]
+const BOX_ARMS: [u8; 128] = [
+    0x11, 0x22, 0x44, 0x88, 0x11, 0x22, 0x44, 0x88, // U+2500
+    0x11, 0x22, 0x44, 0x88, 0x50, 0x60, 0x90, 0xa0, // U+2508
+    0x41, 0x42, 0x81, 0x82, 0x14, 0x24, 0x18, 0x28, // U+2510
+    0x05, 0x06, 0x09, 0x0a, 0x54, 0x64, 0x58, 0x94, // U+2518
+    0x98, 0x68, 0xa4, 0xa8, 0x45, 0x46, 0x49, 0x85, // U+2520
+    0x89, 0x4a, 0x86, 0x8a, 0x51, 0x52, 0x61, 0x62, // U+2528
+    0x91, 0x92, 0xa1, 0xa2, 0x15, 0x16, 0x25, 0x26, // U+2530
+    0x19, 0x1a, 0x29, 0x2a, 0x55, 0x56, 0x65, 0x66, // U+2538
+    0x59, 0x95, 0x99, 0x5a, 0x69, 0x96, 0xa5, 0x6a, // U+2540
+    0xa6, 0x9a, 0xa9, 0xaa, 0x11, 0x22, 0x44, 0x88, // U+2548
+    0x33, 0xcc, 0x70, 0xd0, 0xf0, 0x43, 0xc1, 0xc3, // U+2550
+    0x34, 0x1c, 0x3c, 0x07, 0x0d, 0x0f, 0x74, 0xdc, // U+2558
+    0xfc, 0x47, 0xcd, 0xcf, 0x73, 0xd1, 0xf3, 0x37, // U+2560
+    0x1d, 0x3f, 0x77, 0xdd, 0xff, 0x50, 0x41, 0x05, // U+2568
+    0x14, 0x00, 0x00, 0x00, 0x01, 0x04, 0x10, 0x40, // U+2570
+    0x02, 0x08, 0x20, 0x80, 0x21, 0x84, 0x12, 0x48, // U+2578
+];
+
+/// weights of left, up, right and down arms of a box drawing
+/// character.
+pub(crate) fn box_arms(c: char) -> Option<[u8; 4]> {
+    let i = (c as u32).checked_sub(0x2500).filter(|i| *i < 0x80)?;
+    let arms = BOX_ARMS[i as usize];
+    Some([0, 2, 4, 6].map(|s| (arms >> s) & 3))
+}
+
+/// draw a box drawing character over `ncells` cells.  returns false
+/// for other characters.
+pub(crate) fn print_boxdraw(
+    c: char,
+    gx: isize,
+    gy: isize,
+    ncells: isize,
+    style: &Style,
+    param: &Param,
+) -> bool {
+    let Some([l, u, r, d]) = box_arms(c) else {
+        return false;
+    };
+    let cp = c as u32;
+    let cmd = fg_cmd(style).unwrap_or_default();
+    let w = (ncells * param.csize.width as isize) as f64;
+    let h = param.lheight as f64;
+    let (x0, y0) = (gx as f64, gy as f64);
+    let (cx, cy) = (x0 + w / 2.0, y0 + h / 2.0);
+    let light = (param.csize.height as f64 / 20.0).max(0.4);
+    let thick = |weight: u8| match weight {
+        0 => 0.0,
+        2 => light * 2.5,
+        _ => light,
+    };
+    /* half of the gap between lines of double */
+    let g = param.csize.width as f64 / 5.0;
+
+    let rect = |x1: f64, y1: f64, x2: f64, y2: f64| {
+        println!(
+            " \\put({:.2},{:.2}){{{}\\rule{{{:.2}pt}}{{{:.2}pt}}}}",
+            x1,
+            y1,
+            cmd,
+            x2 - x1,
+            y2 - y1
+        );
+    };
+    let curve = |p1: (f64, f64), p2: (f64, f64), p3: (f64, f64)| {
+        println!(
+            " {{{}\\linethickness{{{:.2}pt}}\\qbezier({:.2},{:.2})({:.2},{:.2})({:.2},{:.2})}}",
+            cmd, light, p1.0, p1.1, p2.0, p2.1, p3.0, p3.1
+        );
+    };
+
+    match cp {
+        0x2571..=0x2573 => {
+            let (x1, y1) = (x0 + w, y0 + h);
+            if cp != 0x2572 {
+                curve((x0, y0), (cx, cy), (x1, y1));
+            }
+            if cp != 0x2571 {
+                curve((x0, y1), (cx, cy), (x1, y0));
+            }
+            return true;
+        }
+        0x256d..=0x2570 => {
+            let px = if l > 0 { x0 } else { x0 + w };
+            let py = if d > 0 { y0 } else { y0 + h };
+            curve((px, cy), (cx, cy), (cx, py));
+            return true;
+        }
+        _ => {}
+    }
+
+    let dashes = match cp {
+        0x2504..=0x2507 => 3,
+        0x2508..=0x250b => 4,
+        0x254c..=0x254f => 2,
+        _ => 0,
+    };
+    if dashes > 0 {
+        let t = thick(l.max(u));
+        for k in 0..dashes {
+            let (a, b) = (k as f64 + 0.25, k as f64 + 0.75);
+            if l > 0 {
+                let step = w / dashes as f64;
+                rect(x0 + step * a, cy - t / 2.0, x0 + step * b, cy + t / 2.0);
+            } else {
+                let step = h / dashes as f64;
+                rect(cx - t / 2.0, y0 + step * a, cx + t / 2.0, y0 + step * b);
+            }
+        }
+        return true;
+    }
+
+    /*
+     * an arm runs from the edge to the centre, and `off` beyond it.
+     * `cross` shifts it sideways, for lines of double.
+     */
+    let seg = |dir: usize, cross: f64, off: f64, t: f64| match dir {
+        0 => rect(x0, cy + cross - t / 2.0, cx + off, cy + cross + t / 2.0),
+        2 => rect(cx - off, cy + cross - t / 2.0, x0 + w, cy + cross + t / 2.0),
+        1 => rect(cx + cross - t / 2.0, cy - off, cx + cross + t / 2.0, y0 + h),
+        _ => rect(cx + cross - t / 2.0, y0, cx + cross + t / 2.0, cy + off),
+    };
+    let all = [l, u, r, d];
+    for (dir, &weight) in all.iter().enumerate() {
+        if weight == 0 {
+            continue;
+        }
+        let opp = all[(dir + 2) % 4];
+        /* perpendicular arms at lower and upper side */
+        let (pneg, ppos) = if dir % 2 == 0 { (d, u) } else { (l, r) };
+        if weight == 3 {
+            for (side, near, far) in [(g, ppos, pneg), (-g, pneg, ppos)] {
+                let off = if near == 3 {
+                    -g
+                } else if opp == 3 {
+                    0.0
+                } else if far == 3 {
+                    g
+                } else {
+                    0.0
+                };
+                seg(dir, side, off + light / 2.0, light);
+            }
+        } else if pneg == 3 || ppos == 3 {
+            /* to the nearer line of double, or to the farther at a corner */
+            let off = if opp > 0 {
+                0.0
+            } else if pneg == ppos {
+                -g
+            } else {
+                g
+            };
+            seg(dir, 0.0, off + light / 2.0, thick(weight));
+        } else {
+            seg(dir, 0.0, thick(pneg.max(ppos)) / 2.0, thick(weight));
+        }
+    }
+    true
+}
+
+/// rectangles of a block element in eighths of the cell, as (x1, y1,
+/// x2, y2) from the lower left.  shades have none.
+pub(crate) fn block_rects(c: char) -> Option<Vec<(u8, u8, u8, u8)>> {
+    let rects = match c as u32 {
+        0x2580 => vec![(0, 4, 8, 8)],
+        k @ 0x2581..=0x2588 => vec![(0, 0, 8, (k - 0x2580) as u8)],
+        k @ 0x2589..=0x258f => vec![(0, 0, (0x2590 - k) as u8, 8)],
+        0x2590 => vec![(4, 0, 8, 8)],
+        0x2591..=0x2593 => vec![],
+        0x2594 => vec![(0, 7, 8, 8)],
+        0x2595 => vec![(7, 0, 8, 8)],
+        0x2596 => vec![(0, 0, 4, 4)],
+        0x2597 => vec![(4, 0, 8, 4)],
+        0x2598 => vec![(0, 4, 4, 8)],
+        0x2599 => vec![(0, 0, 4, 8), (4, 0, 8, 4)],
+        0x259a => vec![(0, 4, 4, 8), (4, 0, 8, 4)],
+        0x259b => vec![(0, 4, 8, 8), (0, 0, 4, 4)],
+        0x259c => vec![(0, 4, 8, 8), (4, 0, 8, 4)],
+        0x259d => vec![(4, 4, 8, 8)],
+        0x259e => vec![(4, 4, 8, 8), (0, 0, 4, 4)],
+        0x259f => vec![(4, 4, 8, 8), (0, 0, 8, 4)],
+        _ => return None,
+    };
+    Some(rects)
+}
+
+/// draw a block element or a Braille pattern over `ncells` cells.
+/// returns false for other characters.
+pub(crate) fn print_blockdraw(
+    c: char,
+    gx: isize,
+    gy: isize,
+    ncells: isize,
+    style: &Style,
+    param: &Param,
+) -> bool {
+    let cmd = fg_cmd(style).unwrap_or_default();
+    let w = (ncells * param.csize.width as isize) as f64;
+    let h = param.lheight as f64;
+    let (x0, y0) = (gx as f64, gy as f64);
+    let rect = |x1: f64, y1: f64, x2: f64, y2: f64| {
+        println!(
+            " \\put({:.2},{:.2}){{{}\\rule{{{:.2}pt}}{{{:.2}pt}}}}",
+            x1,
+            y1,
+            cmd,
+            x2 - x1,
+            y2 - y1
+        );
+    };
+
+    let cp = c as u32;
+    if (0x2800..=0x28ff).contains(&cp) {
+        /* dots 1-2-3-7 on the left and 4-5-6-8 on the right */
+        const DOTS: [(f64, f64); 8] = [
+            (0.25, 0.875),
+            (0.25, 0.625),
+            (0.25, 0.375),
+            (0.75, 0.875),
+            (0.75, 0.625),
+            (0.75, 0.375),
+            (0.25, 0.125),
+            (0.75, 0.125),
+        ];
+        let dia = (w / 2.0).min(h / 4.0) * 0.6;
+        for (bit, (fx, fy)) in DOTS.iter().enumerate() {
+            if cp & (1 << bit) != 0 {
+                println!(
+                    " \\put({:.2},{:.2}){{{}\\circle*{{{:.2}}}}}",
+                    x0 + w * fx,
+                    y0 + h * fy,
+                    cmd,
+                    dia
+                );
+            }
+        }
+        return true;
+    }
+
+    let Some(rects) = block_rects(c) else {
+        return false;
+    };
+    for (x1, y1, x2, y2) in rects {
+        let fx = |v: u8| x0 + w * v as f64 / 8.0;
+        let fy = |v: u8| y0 + h * v as f64 / 8.0;
+        rect(fx(x1), fy(y1), fx(x2), fy(y2));
+    }
+    if (0x2591..=0x2593).contains(&cp) {
+        /* shades by a stipple of 4 by 8 */
+        let (sw, sh) = (w / 4.0, h / 8.0);
+        for i in 0..4 {
+            for j in 0..8 {
+                let on = match cp {
+                    0x2591 => i % 2 == 0 && j % 2 == 0,
+                    0x2592 => (i + j) % 2 == 0,
+                    _ => i % 2 == 0 || j % 2 == 0,
+                };
+                if on {
+                    let (x, y) = (x0 + sw * i as f64, y0 + sh * j as f64);
+                    rect(x, y, x + sw, y + sh);
+                }
+            }
+        }
+    }
+    true
+}
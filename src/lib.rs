@@ -1,17 +1,24 @@
 //
 // fwtype --- fix width typing for TeX picture-env.
 //
-// this code does not support combinatin of UTF-8.
-// you should use 'nkf' or simular programs:
-//      $ nkf --ic=utf8-mac --oc=utf-8 in.txt |thisprogram > out.tex
+// decomposed UTF-8 (e.g., file names on macOS) can be composed by
+// '--normalize nfc' instead of 'nkf' or simular programs:
+//      $ fwtype --normalize nfc in.txt > out.tex
 //
 
 use clap::{App, Arg};
+use encoding_rs::Encoding;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::{self, BufRead, BufReader};
+use unicode_normalization::UnicodeNormalization;
 use unicode_segmentation::UnicodeSegmentation;
 
+mod draw;
+mod eaw;
+mod vt;
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[inline]
@@ -94,14 +101,175 @@ pub enum TokenKind {
     Ascii(String),
     Misc(String),
     Hole(String),
+    Ctrl(String),
+    Tab,
     Skip,
     _Nop,
 }
 
+/// colour of ANSI SGR; palette index or 24-bit RGB.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Default,
+    Index(u8),
+    Rgb(u8, u8, u8),
+}
+
+/// xterm default colours of palette 0..15.
+const XTERM_PALETTE: [(u8, u8, u8); 16] = [
+    (0, 0, 0),
+    (205, 0, 0),
+    (0, 205, 0),
+    (205, 205, 0),
+    (0, 0, 238),
+    (205, 0, 205),
+    (0, 205, 205),
+    (229, 229, 229),
+    (127, 127, 127),
+    (255, 0, 0),
+    (0, 255, 0),
+    (255, 255, 0),
+    (92, 92, 255),
+    (255, 0, 255),
+    (0, 255, 255),
+    (255, 255, 255),
+];
+
+/// colours of a terminal; the default foreground and background,
+/// and the 16 ANSI colours.  emitted as fwfg, fwbg and fwc0..fwc15.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Theme {
+    fg: (u8, u8, u8),
+    bg: (u8, u8, u8),
+    palette: [(u8, u8, u8); 16],
+}
+
+const SOLARIZED_PALETTE: [(u8, u8, u8); 16] = [
+    (7, 54, 66),
+    (220, 50, 47),
+    (133, 153, 0),
+    (181, 137, 0),
+    (38, 139, 210),
+    (211, 54, 130),
+    (42, 161, 152),
+    (238, 232, 213),
+    (0, 43, 54),
+    (203, 75, 22),
+    (88, 110, 117),
+    (101, 123, 131),
+    (131, 148, 150),
+    (108, 113, 196),
+    (147, 161, 161),
+    (253, 246, 227),
+];
+
+const TANGO_PALETTE: [(u8, u8, u8); 16] = [
+    (46, 52, 54),
+    (204, 0, 0),
+    (78, 154, 6),
+    (196, 160, 0),
+    (52, 101, 164),
+    (117, 80, 123),
+    (6, 152, 154),
+    (211, 215, 207),
+    (85, 87, 83),
+    (239, 41, 41),
+    (138, 226, 52),
+    (252, 233, 79),
+    (114, 159, 207),
+    (173, 127, 168),
+    (52, 226, 226),
+    (238, 238, 236),
+];
+
+/// bundled themes for --theme; the first is the default.
+const THEMES: [(&str, Theme); 5] = [
+    (
+        "xterm",
+        Theme {
+            fg: (0, 0, 0),
+            bg: (255, 255, 255),
+            palette: XTERM_PALETTE,
+        },
+    ),
+    (
+        "solarized-dark",
+        Theme {
+            fg: SOLARIZED_PALETTE[12],
+            bg: SOLARIZED_PALETTE[8],
+            palette: SOLARIZED_PALETTE,
+        },
+    ),
+    (
+        "solarized-light",
+        Theme {
+            fg: SOLARIZED_PALETTE[11],
+            bg: SOLARIZED_PALETTE[15],
+            palette: SOLARIZED_PALETTE,
+        },
+    ),
+    (
+        "tango-dark",
+        Theme {
+            fg: TANGO_PALETTE[7],
+            bg: TANGO_PALETTE[0],
+            palette: TANGO_PALETTE,
+        },
+    ),
+    (
+        "tango-light",
+        Theme {
+            fg: TANGO_PALETTE[0],
+            bg: TANGO_PALETTE[15],
+            palette: TANGO_PALETTE,
+        },
+    ),
+];
+
+impl Default for Theme {
+    fn default() -> Self {
+        THEMES[0].1.clone()
+    }
+}
+
+/// RGB of xterm 256 colours; 16..231 are 6x6x6 cube, 232.. are grey.
+fn xterm256_rgb(n: u8) -> (u8, u8, u8) {
+    let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+    match n {
+        0..=15 => XTERM_PALETTE[n as usize],
+        16..=231 => {
+            let i = n - 16;
+            (level(i / 36), level((i / 6) % 6), level(i % 6))
+        }
+        _ => {
+            let g = 8 + (n - 232) * 10;
+            (g, g, g)
+        }
+    }
+}
+
+/// rendition of a cell.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Style {
+    bold: bool,
+    faint: bool,
+    italic: bool,
+    underline: bool,
+    dunderline: bool,
+    strike: bool,
+    overline: bool,
+    reverse: bool,
+    fg: Color,
+    bg: Color,
+    link: Option<String>, /* URI of OSC 8 */
+}
+
 #[derive(Debug, Clone)]
 pub struct Token {
     kind: TokenKind,
     width: isize,
+    style: Style,
 }
 
 impl TokenKind {
@@ -112,6 +280,8 @@ impl TokenKind {
             TokenKind::Ascii(s) => format!("a{:?}", s),
             TokenKind::Misc(s) => format!("m{:?}", s),
             TokenKind::Hole(s) => format!("h{:?}", s),
+            TokenKind::Ctrl(s) => format!("c{:?}", s),
+            TokenKind::Tab => "tab".to_string(),
             TokenKind::Skip => "skip".to_string(),
             TokenKind::_Nop => "nop".to_string(),
         }
@@ -119,6 +289,13 @@ impl TokenKind {
 }
 
 impl Token {
+    pub fn new(kind: TokenKind, width: isize) -> Token {
+        Token {
+            kind,
+            width,
+            style: Style::default(),
+        }
+    }
     pub fn fmt(&self) -> String {
         let k = &self.kind.fmt();
         format!("{}{}", k, &self.width)
@@ -130,14 +307,29 @@ pub struct Row {
     lineno: isize,
     width: isize,
     setret: bool,
+    ffbreak: bool,
+    size: LineSize,
     tokens: Vec<Token>,
 }
 
+/// DEC line attribute, ESC #3..#6 in --vt.  Top and Bottom are the
+/// halves of a double-height line, which is double-width as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LineSize {
+    #[default]
+    Single,
+    Wide,
+    Top,
+    Bottom,
+}
+
 impl Row {
     pub fn clear(&mut self) {
         self.lineno = -1;
         self.width = -1;
         self.setret = false;
+        self.ffbreak = false;
+        self.size = LineSize::Single;
         self.tokens.clear();
     }
     pub fn fmt(self) -> String {
@@ -152,12 +344,269 @@ impl Row {
         for tk in &self.tokens {
             sum += tk.width;
         }
+        if self.size != LineSize::Single {
+            sum *= 2;
+        }
         self.width = sum;
     }
 }
 
 type RowChunk = Vec<Row>;
 
+/// East Asian Width property of a character (UAX #11).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EastAsianWidth {
+    Narrow,
+    Wide,
+    Fullwidth,
+    Halfwidth,
+    Ambiguous,
+    Neutral,
+}
+
+pub fn east_asian_width(c: char) -> EastAsianWidth {
+    let cp = c as u32;
+    match eaw::EAW_TABLE.binary_search_by(|&(lo, hi, _)| {
+        if hi < cp {
+            std::cmp::Ordering::Less
+        } else if lo > cp {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        }
+    }) {
+        Ok(i) => eaw::EAW_TABLE[i].2,
+        Err(_) => EastAsianWidth::Neutral,
+    }
+}
+
+/// treatment of East Asian Ambiguous characters.
+/// CJK-locale terminals draw them wide, others narrow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmbiWidth {
+    Narrow,
+    Wide,
+}
+
+pub fn is_zero_width(c: char) -> bool {
+    let cp = c as u32;
+    eaw::ZERO_WIDTH_TABLE
+        .binary_search_by(|&(lo, hi)| {
+            if hi < cp {
+                std::cmp::Ordering::Less
+            } else if lo > cp {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+/// entries of --widthmap; the later wins.
+fn widthmap_lookup(c: char, param: &Param) -> Option<isize> {
+    let cp = c as u32;
+    param
+        .widthmap
+        .iter()
+        .rev()
+        .find(|&&(lo, hi, _)| lo <= cp && cp <= hi)
+        .map(|&(_, _, w)| w)
+}
+
+/// number of cells a character occupies in the terminal.
+/// entries of --widthmap are consulted first.
+fn cell_width(c: char, param: &Param) -> isize {
+    if let Some(w) = widthmap_lookup(c, param) {
+        return w;
+    }
+    match east_asian_width(c) {
+        EastAsianWidth::Wide | EastAsianWidth::Fullwidth => 2,
+        EastAsianWidth::Ambiguous => match param.ambiguous {
+            AmbiWidth::Wide => 2,
+            AmbiWidth::Narrow => 1,
+        },
+        EastAsianWidth::Narrow | EastAsianWidth::Halfwidth | EastAsianWidth::Neutral => 1,
+    }
+}
+
+/// number of cells a grapheme cluster occupies; that of its base
+/// character, unless a variation selector forces text (VS15) or
+/// emoji (VS16) presentation.  Clusters made of zero-width characters
+/// only (ZWSP, ZWJ, stray combining marks, ...) occupy no cell.
+/// extending characters which take a cell of their own, such as
+/// halfwidth sound marks (U+FF9E, U+FF9F), add their width.
+fn grapheme_width(g: &str, param: &Param) -> isize {
+    let Some(base) = g.chars().next() else {
+        return 0;
+    };
+    if let Some(w) = widthmap_lookup(base, param) {
+        return w;
+    }
+    if is_zero_width(base) {
+        return 0;
+    }
+    if g.contains('\u{FE0F}') {
+        return 2;
+    }
+    if g.contains('\u{FE0E}') {
+        return 1;
+    }
+    let mut width = cell_width(base, param);
+    let mut prev = base;
+    for c in g.chars().skip(1) {
+        /* not a character joined by ZWJ, an emoji modifier nor a
+        Hangul vowel or final jamo */
+        let joined = prev == '\u{200D}'
+            || matches!(c as u32, 0x1F3FB..=0x1F3FF | 0x1160..=0x11FF | 0xD7B0..=0xD7FF);
+        if !joined && !is_zero_width(c) {
+            width += cell_width(c, param);
+        }
+        prev = c;
+    }
+    width
+}
+
+/// normalization form applied to input lines before parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalize {
+    None,
+    Nfc,
+    Nfkc,
+}
+
+fn normalize_line(line: String, form: Normalize) -> String {
+    match form {
+        Normalize::None => line,
+        Normalize::Nfc => line.nfc().collect(),
+        Normalize::Nfkc => line.nfkc().collect(),
+    }
+}
+
+/// display of control characters other than TAB, ESC and GS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CtrlMode {
+    Raw,
+    Caret,
+    Picture,
+}
+
+/// where rows of a long line break; at wmax, or at the last word
+/// boundary before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    Char,
+    Word,
+}
+
+/// display text of a control character as cat -v shows, or by
+/// Control Pictures (U+2400..).  C1 controls have no pictures.
+fn ctrl_text(c: char, mode: CtrlMode) -> Option<String> {
+    let cp = c as u32;
+    match (mode, cp) {
+        (CtrlMode::Raw, _) => None,
+        (CtrlMode::Caret, 0x00..=0x1F) => Some(format!("^{}", char::from_u32(cp + 0x40)?)),
+        (CtrlMode::Caret, 0x7F) => Some("^?".to_string()),
+        (CtrlMode::Picture, 0x00..=0x1F) => char::from_u32(0x2400 + cp).map(String::from),
+        (CtrlMode::Picture, 0x7F) => Some("\u{2421}".to_string()),
+        (_, 0x80..=0x9F) => Some(format!("M-^{}", char::from_u32(cp - 0x40)?)),
+        _ => None,
+    }
+}
+
+/// count Ambiguous characters appeared in rows, for --ambreport.
+fn count_ambiguous(chunk: &RowChunk, seen: &mut BTreeMap<char, usize>) {
+    for r in chunk {
+        for tk in &r.tokens {
+            if let TokenKind::Misc(s) = &tk.kind
+                && let Some(c) = s.chars().next()
+                && east_asian_width(c) == EastAsianWidth::Ambiguous
+            {
+                *seen.entry(c).or_insert(0) += 1;
+            }
+        }
+    }
+}
+
+/// byte ranges of URLs and file paths in `line`.  trailing
+/// punctuation and unbalanced closing brackets are not a part.  a
+/// path starts a word, so that "</div>" is not one.
+fn find_links(line: &str) -> Vec<(usize, usize, String)> {
+    let urlchar = |c: char| c.is_ascii_graphic() && !"<>\"`{}|\\^".contains(c);
+    let mut links = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        let rest = &line[i..];
+        let scheme = ["https://", "http://", "mailto:", "/"]
+            .into_iter()
+            .find(|p| rest.starts_with(p));
+        let boundary = match scheme {
+            Some("/") => {
+                line[..i].is_empty()
+                    || line[..i].ends_with(|c: char| c.is_whitespace() || "([{'\"=".contains(c))
+            }
+            _ => !line[..i].ends_with(|c: char| c.is_ascii_alphanumeric() || "/._-~".contains(c)),
+        };
+        let Some(scheme) = scheme.filter(|_| boundary) else {
+            i += rest.chars().next().map_or(1, char::len_utf8);
+            continue;
+        };
+        let mut n = rest.find(|c: char| !urlchar(c)).unwrap_or(rest.len());
+        loop {
+            let t = &rest[..n];
+            let unbalanced =
+                |o: char, c: char| t.ends_with(c) && t.matches(o).count() < t.matches(c).count();
+            if t.ends_with(['.', ',', ';', ':', '!', '?', '\'', '"'])
+                || unbalanced('(', ')')
+                || unbalanced('[', ']')
+            {
+                n -= 1;
+            } else {
+                break;
+            }
+        }
+        let t = &rest[..n];
+        /* a path needs a name after the slash, e.g., not "a / b" */
+        let body = t.get(scheme.len()..).unwrap_or_default();
+        if !body.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+            i += scheme.len();
+            continue;
+        }
+        let uri = match scheme {
+            "/" => format!("file://{}", t),
+            _ => t.to_string(),
+        };
+        links.push((i, i + n, uri));
+        i += n;
+    }
+    links
+}
+
+/// give links to the cells of URLs found in a line, for --autolink.
+/// cells in OSC 8 hyperlinks are left as they are.
+fn autolink(tokens: &mut [Token]) {
+    let mut line = String::new();
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    for (i, tk) in tokens.iter().enumerate() {
+        match &tk.kind {
+            TokenKind::Ascii(s) | TokenKind::Misc(s) => {
+                starts.push((line.len(), i));
+                line.push_str(s);
+            }
+            _ if tk.width > 0 || matches!(tk.kind, TokenKind::Tab) => line.push(' '),
+            _ => {}
+        }
+    }
+    for (b, e, uri) in find_links(&line) {
+        for &(pos, i) in &starts {
+            let tk = &mut tokens[i];
+            if pos >= b && pos < e && tk.style.link.is_none() {
+                tk.style.link = Some(uri.clone());
+            }
+        }
+    }
+}
+
 /*
 fn fmt_row(row: &Row) -> String {
     row.tokens.iter()
@@ -213,90 +662,577 @@ fn view_chunk(prefix: &str, chunk: &RowChunk) {
 }
 */
 
-fn parse_line(rawstr: &str, tabstop: isize, wcolumn: isize) -> RowChunk {
-    let mut iter = rawstr.graphemes(true).peekable();
-    let mut c = 0;
-    let mut x = 0;
-    let mut _y = 0;
+/// start of an escape sequence; ESC, or 8-bit CSI and control
+/// strings when `c1` is set.
+fn is_esc_intro(c: char, c1: bool) -> bool {
+    c == '\x1b'
+        || c1
+            && matches!(
+                c,
+                '\u{9b}' | '\u{9d}' | '\u{90}' | '\u{98}' | '\u{9e}' | '\u{9f}'
+            )
+}
+
+/// length in bytes of the escape sequence at the head of `s`, by
+/// ECMA-48; CSI, control strings (OSC, DCS, SOS, PM, APC) and escape
+/// sequences of ESC, intermediates and a final byte.  a malformed
+/// sequence ends just before the offending character, and an
+/// unterminated control string runs to the end of line.
+fn scan_escape(s: &str) -> usize {
+    enum Intro {
+        Csi,
+        Str,
+        Esc,
+    }
+    let mut chars = s.char_indices().peekable();
+    let Some((_, c0)) = chars.next() else {
+        return 0;
+    };
+    let intro = match c0 {
+        '\x1b' => match chars.peek() {
+            Some(&(_, '[')) => {
+                chars.next();
+                Intro::Csi
+            }
+            Some(&(_, ']' | 'P' | 'X' | '^' | '_')) => {
+                chars.next();
+                Intro::Str
+            }
+            _ => Intro::Esc,
+        },
+        '\u{9b}' => Intro::Csi,
+        _ => Intro::Str,
+    };
+
+    match intro {
+        Intro::Csi => {
+            let mut inter = false;
+            for (i, c) in chars {
+                match c {
+                    '\x30'..='\x3f' if !inter => {}
+                    '\x20'..='\x2f' => inter = true,
+                    '\x40'..='\x7e' => return i + 1,
+                    _ => return i,
+                }
+            }
+        }
+        Intro::Str => {
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\x07' | '\u{9c}' => return i + c.len_utf8(),
+                    '\x1b' => {
+                        return match chars.peek() {
+                            Some(&(_, '\\')) => i + 2,
+                            _ => i,
+                        };
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Intro::Esc => {
+            let mut end = c0.len_utf8();
+            for (i, c) in chars {
+                match c {
+                    '\x20'..='\x2f' => {}
+                    '\x30'..='\x7e' => return i + 1,
+                    _ => return end,
+                }
+                end = i + 1;
+            }
+            return end;
+        }
+    }
+    s.len()
+}
+
+/// split a line into text and escape sequences.  C1 controls are
+/// introducers only with `c1`; otherwise they are left to --ctrl.
+fn split_escapes(s: &str, c1: bool) -> Vec<(&str, bool)> {
+    let mut segs = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while let Some(c) = s[i..].chars().next() {
+        if is_esc_intro(c, c1) {
+            if start < i {
+                segs.push((&s[start..i], false));
+            }
+            let n = scan_escape(&s[i..]).max(c.len_utf8());
+            segs.push((&s[i..i + n], true));
+            i += n;
+            start = i;
+        } else {
+            i += c.len_utf8();
+        }
+    }
+    if start < s.len() {
+        segs.push((&s[start..], false));
+    }
+    segs
+}
+
+/// parameters of SGR; CSI of digits, semicolons and colons ending by m.
+fn sgr_params(seq: &str) -> Option<&str> {
+    let body = seq
+        .strip_prefix("\x1b[")
+        .or_else(|| seq.strip_prefix('\u{9b}'))?;
+    let params = body.strip_suffix('m')?;
+    if params
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == ';' || c == ':')
+    {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+/// URI of OSC 8 hyperlink, "ESC ] 8 ; params ; URI ST".  empty URI
+/// closes the link.
+fn osc8_uri(seq: &str) -> Option<&str> {
+    let body = seq
+        .strip_prefix("\x1b]8;")
+        .or_else(|| seq.strip_prefix("\u{9d}8;"))?;
+    let body = body
+        .strip_suffix('\x07')
+        .or_else(|| body.strip_suffix("\x1b\\"))
+        .or_else(|| body.strip_suffix('\u{9c}'))?;
+    body.split_once(';').map(|(_, uri)| uri)
+}
+
+/// sequences fwtype understands; SGR, OSC 8, and EL that grep(1)
+/// emits after colours.  others are dropped, and listed by --escwarn.
+fn is_known_escape(seq: &str) -> bool {
+    if sgr_params(seq).is_some() || osc8_uri(seq).is_some() {
+        return true;
+    }
+    matches!(seq, "\x1b[K" | "\x1b[0K" | "\u{9b}K")
+}
+
+#[allow(clippy::while_let_loop)]
+fn tokenize_line(rawstr: &str, sgr: &mut Style, param: &Param) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
     let mut tk: Token;
+    let mut backspaced = false;
+    let c1 = param.ansi || param.hyperlink || param.escwarn;
+    for (seg, is_escape) in split_escapes(rawstr, c1) {
+        if is_escape {
+            if param.ansi
+                && let Some(p) = sgr_params(seg)
+            {
+                apply_sgr(sgr, p);
+            }
+            if param.hyperlink
+                && let Some(uri) = osc8_uri(seg)
+            {
+                sgr.link = Some(uri.to_string()).filter(|u| !u.is_empty());
+            }
+            /* a state change, which occupies no cell */
+            tokens.push(Token::new(TokenKind::Escape(seg.to_string()), 0));
+            continue;
+        }
+
+        let mut iter = seg.graphemes(true);
+        loop {
+            let Some(q) = iter.next() else { break };
+
+            if q.is_ascii() {
+                if q == "\t" {
+                    tk = Token::new(TokenKind::Tab, 0);
+                } else if q == "\x08" && param.overstrike {
+                    backspaced = true;
+                    continue;
+                } else if q == "\x1d" {
+                    let Some(q2) = iter.next() else { break };
+                    tk = Token::new(TokenKind::Hole(q2.to_string()), 4);
+                } else if let Some(text) = q.chars().next().and_then(|c| ctrl_text(c, param.ctrl)) {
+                    let w = text.chars().map(|c| cell_width(c, param)).sum();
+                    tk = Token::new(TokenKind::Ctrl(text), w);
+                } else {
+                    let w = q.chars().next().and_then(|c| widthmap_lookup(c, param));
+                    tk = Token::new(TokenKind::Ascii(q.to_string()), w.unwrap_or(1));
+                }
+            } else if let Some(text) = q.chars().next().and_then(|c| ctrl_text(c, param.ctrl)) {
+                let w = text.chars().map(|c| cell_width(c, param)).sum();
+                tk = Token::new(TokenKind::Ctrl(text), w);
+            } else {
+                tk = Token::new(TokenKind::Misc(q.to_string()), grapheme_width(q, param));
+            }
+            if param.ansi {
+                tk.style = sgr.clone();
+            }
+            tk.style.link = sgr.link.clone().filter(|_| param.hyperlink);
+
+            if tk.width == 0 && !matches!(tk.kind, TokenKind::Tab) {
+                continue;
+            }
+            if backspaced {
+                backspaced = false;
+                if let Some(prev) = tokens.last_mut() {
+                    overstrike(prev, tk);
+                    continue;
+                }
+            }
+            tokens.push(tk);
+        }
+    }
+    tokens
+}
+
+/// extended colour of SGR 38/48; "5;n" or "2;r;g;b".  the colon
+/// form may have colour space id as "2:id:r:g:b".  returns the colour
+/// and the number of parameters consumed.
+fn sgr_color(args: &[Option<u32>], colon: bool) -> (Color, usize) {
+    let arg = |i: usize| args.get(i).copied().flatten().unwrap_or(0).min(255) as u8;
+    match args.first().copied().flatten() {
+        Some(5) if args.len() >= 2 => (Color::Index(arg(1)), 2),
+        Some(2) if colon && args.len() >= 5 => (Color::Rgb(arg(2), arg(3), arg(4)), 5),
+        Some(2) if args.len() >= 4 => (Color::Rgb(arg(1), arg(2), arg(3)), 4),
+        _ => (Color::Default, args.len()),
+    }
+}
+
+/// apply parameters of SGR (CSI ... m) to the current style.
+fn apply_sgr(style: &mut Style, params: &str) {
+    let groups: Vec<Vec<Option<u32>>> = params
+        .split(';')
+        .map(|g| g.split(':').map(|v| v.parse().ok()).collect())
+        .collect();
+    let mut i = 0;
+    while i < groups.len() {
+        let g = &groups[i];
+        let code = g[0].unwrap_or(0);
+        i += 1;
+        match code {
+            0 => {
+                /* a hyperlink is not a rendition */
+                *style = Style {
+                    link: style.link.take(),
+                    ..Default::default()
+                }
+            }
+            1 => style.bold = true,
+            2 => style.faint = true,
+            3 => style.italic = true,
+            4 => {
+                /* 4:0 none, 4:2 double, 4:3 curly and so on */
+                let kind = g.get(1).copied().flatten().unwrap_or(1);
+                style.underline = kind != 0 && kind != 2;
+                style.dunderline = kind == 2;
+            }
+            7 => style.reverse = true,
+            9 => style.strike = true,
+            21 => {
+                style.underline = false;
+                style.dunderline = true;
+            }
+            22 => {
+                style.bold = false;
+                style.faint = false;
+            }
+            23 => style.italic = false,
+            24 => {
+                style.underline = false;
+                style.dunderline = false;
+            }
+            27 => style.reverse = false,
+            29 => style.strike = false,
+            53 => style.overline = true,
+            55 => style.overline = false,
+            30..=37 => style.fg = Color::Index((code - 30) as u8),
+            39 => style.fg = Color::Default,
+            40..=47 => style.bg = Color::Index((code - 40) as u8),
+            49 => style.bg = Color::Default,
+            90..=97 => style.fg = Color::Index((code - 90 + 8) as u8),
+            100..=107 => style.bg = Color::Index((code - 100 + 8) as u8),
+            38 | 48 => {
+                let (color, used) = if g.len() > 1 {
+                    /* colon form; 38:5:n or 38:2::r:g:b */
+                    let (c, _) = sgr_color(&g[1..], true);
+                    (c, 0)
+                } else {
+                    let rest: Vec<Option<u32>> = groups[i..].iter().map(|h| h[0]).collect();
+                    sgr_color(&rest, false)
+                };
+                i += used;
+                if code == 38 {
+                    style.fg = color;
+                } else {
+                    style.bg = color;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// nroff overstrike; "X\bX" is bold, "_\bX" is underlined X.
+/// other combinations leave the later character, as less(1) does.
+fn overstrike(prev: &mut Token, tk: Token) {
+    let text = |t: &Token| match &t.kind {
+        TokenKind::Ascii(s) | TokenKind::Misc(s) => Some(s.clone()),
+        _ => None,
+    };
+    let (Some(p), Some(q)) = (text(prev), text(&tk)) else {
+        *prev = tk;
+        return;
+    };
+    if p == q {
+        prev.style.bold = true;
+    } else if p == "_" {
+        let style = Style {
+            underline: true,
+            ..prev.style.clone()
+        };
+        *prev = Token { style, ..tk };
+    } else if q == "_" {
+        prev.style.underline = true;
+    } else {
+        let style = prev.style.clone();
+        *prev = Token { style, ..tk };
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Cell {
+    Empty,
+    Head(Token),
+    Tail,
+}
+
+/// overlay segments separated by bare CR, as a terminal returns the
+/// cursor to column 0 and overwrites the cells.  the final visible
+/// state of the line remains.
+fn overwrite_cr(segments: Vec<Vec<Token>>, param: &Param) -> Vec<Token> {
+    let tabstop = param.tabstop as isize;
+    let mut cells: Vec<Cell> = Vec::new();
+    for seg in segments {
+        let mut x: usize = 0;
+        for tk in seg {
+            if let TokenKind::Tab = tk.kind {
+                x = ((x as isize / tabstop) * tabstop + tabstop) as usize;
+                continue;
+            }
+            let w = tk.width as usize;
+            if w == 0 {
+                continue;
+            }
+            if cells.len() < x + w {
+                cells.resize(x + w, Cell::Empty);
+            }
+            /* break wide characters partially overwritten */
+            if let Cell::Tail = cells[x] {
+                let mut h = x;
+                while h > 0 && matches!(cells[h], Cell::Tail) {
+                    cells[h] = Cell::Empty;
+                    h -= 1;
+                }
+                cells[h] = Cell::Empty;
+            }
+            let mut t = x + w;
+            while t < cells.len() && matches!(cells[t], Cell::Tail) {
+                cells[t] = Cell::Empty;
+                t += 1;
+            }
+            for cell in cells.iter_mut().skip(x + 1).take(w - 1) {
+                *cell = Cell::Tail;
+            }
+            cells[x] = Cell::Head(tk);
+            x += w;
+        }
+    }
+
+    while let Some(Cell::Empty) = cells.last() {
+        cells.pop();
+    }
+    cells
+        .into_iter()
+        .filter_map(|cell| match cell {
+            Cell::Head(tk) => Some(tk),
+            Cell::Tail => None,
+            Cell::Empty => Some(Token::new(TokenKind::Skip, 1)),
+        })
+        .collect()
+}
+
+/// a row may break after this token in --wrap word.
+fn is_wrap_point(tk: &Token) -> bool {
+    match &tk.kind {
+        TokenKind::Tab => true,
+        TokenKind::Ascii(s) => " -/,.;:!?)]}>|&".contains(s.as_str()),
+        TokenKind::Misc(s) => matches!(s.as_str(), "\u{3000}" | "、" | "。"),
+        _ => false,
+    }
+}
+
+/// characters which must not start a row, by Japanese kinsoku rules.
+const KINSOKU_HEAD: &str = "、。，．・：；？！‐ー―）］｝〕〉》」』】〙〗〟’”｠»\
+    ぁぃぅぇぉっゃゅょゎゕゖァィゥェォッャュョヮヵヶㇰㇱㇲㇳㇴㇵㇶㇷㇸㇹㇺㇻㇼㇽㇾㇿ\
+    々〻ゝゞヽヾ〜～…‥)]},.:;!?｡､｣ｰｧｨｩｪｫｬｭｮｯ";
+
+/// characters which must not end a row.
+const KINSOKU_TAIL: &str = "（［｛〔〈《「『【〘〖〝‘“｟«([{｢";
+
+/// punctuation which may hang into the margin by --hanging.
+const HANGING: &str = "、。，．,.｡､";
+
+/// the token is one of characters in `set`.
+fn token_in(tk: &Token, set: &str) -> bool {
+    match &tk.kind {
+        TokenKind::Ascii(s) | TokenKind::Misc(s) => {
+            let mut cs = s.chars();
+            cs.next()
+                .filter(|_| cs.next().is_none())
+                .is_some_and(|c| set.contains(c))
+        }
+        _ => false,
+    }
+}
+
+/// break tokens of a logical line into rows of wmax columns.
+fn wrap_tokens(tokens: Vec<Token>, param: &Param) -> RowChunk {
+    let tabstop = param.tabstop as isize;
+    let wcolumn = param.wmax as isize;
+    let mut x = 0;
     let mut rchk: RowChunk = Vec::new();
     let mut currow: Row = Row {
         lineno: -1,
         width: -1,
         setret: false,
+        ffbreak: false,
+        size: LineSize::Single,
         tokens: Vec::new(),
     };
-    loop {
-        let Some(q) = iter.next() else { break };
-
-        if q.is_ascii() {
-            if q == "\t" {
-                let nx = ((x) / tabstop) * tabstop + tabstop;
-                tk = Token {
-                    kind: TokenKind::Skip,
-                    width: nx - x,
-                };
-            } else if q == "\x1b" {
-                let mut seq = String::from(q);
-                while let Some(&next) = iter.peek() {
-                    seq.push_str(next);
-                    iter.next();
-                    if next.ends_with('m') {
+    /* width from each token to the end of its word */
+    let mut wordrest = vec![0; tokens.len()];
+    let mut acc = 0;
+    for (i, tk) in tokens.iter().enumerate().rev() {
+        acc = match &tk.kind {
+            TokenKind::Tab => 0,
+            TokenKind::Ascii(s) if s == " " => 0,
+            _ if is_wrap_point(tk) => tk.width,
+            _ => acc + tk.width,
+        };
+        wordrest[i] = acc;
+    }
+    let is_space = |t: &Token| matches!(&t.kind, TokenKind::Ascii(s) if s == " ");
+    let mut dropspace = false;
+    for (mut tk, rest) in tokens.into_iter().zip(wordrest) {
+        let space = is_space(&tk);
+        if dropspace && space {
+            continue;
+        }
+        dropspace = false;
+        if let TokenKind::Tab = tk.kind {
+            let nx = ((x) / tabstop) * tabstop + tabstop;
+            tk.width = nx - x;
+        }
+
+        if x + tk.width > wcolumn {
+            /* a word moves to the next row unless it is too long */
+            let mut carry = Vec::new();
+            if param.wrap == WrapMode::Word
+                && !space
+                && let Some(k) = currow.tokens.iter().rposition(is_wrap_point)
+            {
+                let w: isize = currow.tokens[k + 1..].iter().map(|t| t.width).sum();
+                if w + rest <= wcolumn {
+                    carry = currow.tokens.split_off(k + 1);
+                }
+            }
+            if param.kinsoku && carry.is_empty() && param.hanging && token_in(&tk, HANGING) {
+                /* hang over wmax; the row is wider by the cell, for
+                the canvas to keep it inside the frame and the mark */
+                currow.tokens.push(tk);
+                currow.setret = true;
+                currow.calcwidth();
+                rchk.push(currow.clone());
+                currow.clear();
+                x = 0;
+                continue;
+            }
+            if param.kinsoku {
+                /* push back characters until both ends of rows are allowed */
+                loop {
+                    let head = carry.iter().find(|t| t.width > 0).unwrap_or(&tk);
+                    let Some(tail) = currow.tokens.iter().rposition(|t| t.width > 0) else {
+                        break;
+                    };
+                    let w: isize = currow.tokens[tail..]
+                        .iter()
+                        .chain(&carry)
+                        .map(|t| t.width)
+                        .sum();
+                    if tail == 0
+                        || w + tk.width > wcolumn
+                        || !(token_in(head, KINSOKU_HEAD)
+                            || token_in(&currow.tokens[tail], KINSOKU_TAIL))
+                    {
                         break;
                     }
+                    let mut moved = currow.tokens.split_off(tail);
+                    moved.append(&mut carry);
+                    carry = moved;
+                }
+            }
+            if param.wrap == WrapMode::Word && !carry.is_empty() {
+                /* spaces before the carried word end no row either */
+                while currow.tokens.last().is_some_and(is_space) {
+                    currow.tokens.pop();
                 }
-                tk = Token {
-                    kind: TokenKind::Escape(seq),
-                    width: 1,
-                };
-            } else if q == "\x1d" {
-                let Some(q2) = iter.next() else { break };
-                tk = Token {
-                    kind: TokenKind::Hole(q2.to_string()),
-                    width: 4,
-                };
-            } else {
-                tk = Token {
-                    kind: TokenKind::Ascii(q.to_string()),
-                    width: 1,
-                };
             }
-        } else {
-            tk = Token {
-                kind: TokenKind::Misc(q.to_string()),
-                width: 2,
-            };
-        }
-
-        if x + tk.width > wcolumn {
             currow.setret = true;
             currow.calcwidth();
             rchk.push(currow.clone());
 
-            currow.tokens.clear();
             currow.clear();
             x = 0;
-
-            _y += 1;
+            for mut c in carry {
+                if let TokenKind::Tab = c.kind {
+                    c.width = ((x) / tabstop) * tabstop + tabstop - x;
+                }
+                x += c.width;
+                currow.tokens.push(c);
+            }
+            if x > 0
+                && let TokenKind::Tab = tk.kind
+            {
+                tk.width = ((x) / tabstop) * tabstop + tabstop - x;
+            }
+            if param.wrap == WrapMode::Word && space {
+                /* spaces at the break start no row */
+                dropspace = true;
+                continue;
+            }
         }
         x += tk.width;
         currow.tokens.push(tk);
-
-        c += 1;
     }
 
-    if (!currow.tokens.is_empty()) || c == 0 {
+    if !currow.tokens.is_empty() || rchk.is_empty() {
         currow.calcwidth();
-        rchk.push(currow.clone());
-
-        currow.tokens.clear();
-
-        _y += 1;
+        rchk.push(currow);
     }
 
     rchk
 }
 
+fn parse_line(rawstr: &str, sgr: &mut Style, param: &Param) -> RowChunk {
+    let mut tokens = if param.croverwrite && rawstr.contains('\r') {
+        let segments = rawstr
+            .split('\r')
+            .map(|seg| tokenize_line(seg, sgr, param))
+            .collect();
+        overwrite_cr(segments, param)
+    } else {
+        tokenize_line(rawstr, sgr, param)
+    };
+    if param.autolink {
+        autolink(&mut tokens);
+    }
+    wrap_tokens(tokens, param)
+}
+
 #[derive(Debug)]
 pub struct Param {
     font: String,
@@ -323,6 +1259,10 @@ pub struct Param {
     rightgap: String,
     lnooffset: usize,
     lnowidth: usize,
+    ambiguous: AmbiWidth,
+    widthmap: Vec<(u32, u32, isize)>,
+    normalize: Normalize,
+    encoding: Option<&'static Encoding>,
     //
     verbose: bool,
     spcmarking: bool,
@@ -330,6 +1270,34 @@ pub struct Param {
     numbering: bool,
     standalone: bool,
     pagebreaking: bool,
+    ambreport: bool,
+    croverwrite: bool,
+    overstrike: bool,
+    formfeed: bool,
+    ffnewpage: bool,
+    ctrl: CtrlMode,
+    wrap: WrapMode,
+    kinsoku: bool,
+    hanging: bool,
+    ansi: bool,
+    escwarn: bool,
+    theme: Theme,
+    mono: bool,
+    fill: bool,
+    colors: bool, /* define fwfg, fwbg and fwcN */
+    boxdraw: bool,
+    blockdraw: bool,
+    hyperlink: bool,
+    autolink: bool,
+    vt: Option<(usize, usize)>,
+}
+
+/// parameters of an empty command line; the defaults of get_args.
+#[cfg(test)]
+impl Default for Param {
+    fn default() -> Self {
+        get_args_from(["fwtype"]).unwrap().params
+    }
 }
 
 #[derive(Debug)]
@@ -358,6 +1326,14 @@ fn arg_usize(
 
 // --------------------------------------------------
 pub fn get_args() -> MyResult<Config> {
+    get_args_from(std::env::args_os())
+}
+
+fn get_args_from<I, T>(args: I) -> MyResult<Config>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(long_version_static())
         .author("Ken-ichi Chinen https://github.com/k-chinen/fwtype")
@@ -553,33 +1529,200 @@ pub fn get_args() -> MyResult<Config> {
                 .help("Insert preamble and begin/end document in first"),
         )
         .arg(
-            Arg::with_name("frames")
-                .short("f")
-                .long("frames")
+            Arg::with_name("ambiguous")
+                .long("ambiguous")
                 .takes_value(true)
-                .help("set of frames <default 15>")
+                .possible_values(&["narrow", "wide"])
+                .help("width of East Asian Ambiguous characters")
+                .default_value("narrow"),
+        )
+        .arg(
+            Arg::with_name("ambreport")
+                .long("ambreport")
+                .takes_value(false)
+                .help("Report Ambiguous characters found in input"),
+        )
+        .arg(
+            Arg::with_name("widthmap")
+                .long("widthmap")
+                .takes_value(true)
+                .help("width override file; lines of \"U+E0A0..E0B3 1\"")
                 .multiple(true)
                 .number_of_values(1),
         )
         .arg(
-            Arg::with_name("files")
-                .value_name("FILE")
-                .help("Input file(s)")
-                .multiple(true)
-                .default_value("-"),
+            Arg::with_name("normalize")
+                .long("normalize")
+                .takes_value(true)
+                .possible_values(&["none", "nfc", "nfkc"])
+                .help("Unicode normalization of input")
+                .default_value("none"),
         )
-        .get_matches();
-
-    if matches.is_present("version") {
-        // clap process them automatically
-    }
-
-    let verbose = matches.is_present("verbose");
-
-    if verbose {
-        eprintln!("VEBOSE mode");
-    }
-
+        .arg(
+            Arg::with_name("encoding")
+                .short("e")
+                .long("encoding")
+                .takes_value(true)
+                .help("input encoding; auto, utf-8, shift_jis, euc-jp, iso-2022-jp or latin1")
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("croverwrite")
+                .long("cr")
+                .takes_value(false)
+                .help("Carriage return overwrites the line from column 0"),
+        )
+        .arg(
+            Arg::with_name("overstrike")
+                .long("overstrike")
+                .takes_value(false)
+                .help("Backspace overstrike as bold and underline, e.g., man(1)"),
+        )
+        .arg(
+            Arg::with_name("formfeed")
+                .long("formfeed")
+                .takes_value(false)
+                .help("Form feed closes the picture and starts a new one"),
+        )
+        .arg(
+            Arg::with_name("ffnewpage")
+                .long("ffnewpage")
+                .takes_value(false)
+                .help("Insert a page break at each form feed. See --formfeed"),
+        )
+        .arg(
+            Arg::with_name("ctrl")
+                .long("ctrl")
+                .takes_value(true)
+                .possible_values(&["raw", "caret", "picture"])
+                .help("display of control characters; ^G or \u{2407}")
+                .default_value("raw"),
+        )
+        .arg(
+            Arg::with_name("wrap")
+                .long("wrap")
+                .takes_value(true)
+                .possible_values(&["char", "word"])
+                .help("break of long lines; at wmax, or at a word boundary")
+                .default_value("char"),
+        )
+        .arg(
+            Arg::with_name("kinsoku")
+                .long("kinsoku")
+                .takes_value(false)
+                .help("Wrap by Japanese kinsoku rules; no 、。） at the head nor （「 at the tail of rows"),
+        )
+        .arg(
+            Arg::with_name("hanging")
+                .long("hanging")
+                .takes_value(false)
+                .help("Hang 、。 in the right margin instead of pushing back. See --kinsoku"),
+        )
+        .arg(
+            Arg::with_name("ansi")
+                .short("a")
+                .long("ansi")
+                .takes_value(false)
+                .help("Interpret ANSI SGR sequences; needs xcolor"),
+        )
+        .arg(
+            Arg::with_name("theme")
+                .long("theme")
+                .takes_value(true)
+                .value_name("NAME|FILE")
+                .help("colour theme for --ansi; xterm, solarized-dark, solarized-light, tango-dark, tango-light or a file"),
+        )
+        .arg(
+            Arg::with_name("fg")
+                .long("fg")
+                .takes_value(true)
+                .value_name("#RRGGBB")
+                .help("default foreground colour; overrides --theme"),
+        )
+        .arg(
+            Arg::with_name("bg")
+                .long("bg")
+                .takes_value(true)
+                .value_name("#RRGGBB")
+                .help("default background colour; overrides --theme"),
+        )
+        .arg(
+            Arg::with_name("fill")
+                .long("fill")
+                .takes_value(false)
+                .help("Fill the text area by the background colour, as a terminal; needs xcolor"),
+        )
+        .arg(
+            Arg::with_name("mono")
+                .long("mono")
+                .takes_value(false)
+                .help("Render ANSI colours by weight, italic, underline, grey and hatching for print"),
+        )
+        .arg(
+            Arg::with_name("escwarn")
+                .long("escwarn")
+                .takes_value(false)
+                .help("Warn escape sequences dropped without interpretation"),
+        )
+        .arg(
+            Arg::with_name("boxdraw")
+                .long("boxdraw")
+                .takes_value(false)
+                .help("Draw box drawing characters by lines which join to the neighbours"),
+        )
+        .arg(
+            Arg::with_name("blockdraw")
+                .long("blockdraw")
+                .takes_value(false)
+                .help("Draw block elements and Braille patterns by rectangles and dots"),
+        )
+        .arg(
+            Arg::with_name("hyperlink")
+                .long("hyperlink")
+                .takes_value(false)
+                .help("Make OSC 8 hyperlinks clickable; needs hyperref"),
+        )
+        .arg(
+            Arg::with_name("autolink")
+                .long("autolink")
+                .takes_value(false)
+                .help("Make URLs and file paths clickable. See --hyperlink"),
+        )
+        .arg(
+            Arg::with_name("vt")
+                .long("vt")
+                .takes_value(true)
+                .value_name("COLSxROWS")
+                .help("Play input on a virtual terminal and typeset the final screen, e.g., 80x24"),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .short("f")
+                .long("frames")
+                .takes_value(true)
+                .help("set of frames <default 15>")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("files")
+                .value_name("FILE")
+                .help("Input file(s)")
+                .multiple(true)
+                .default_value("-"),
+        )
+        .get_matches_from(args);
+
+    if matches.is_present("version") {
+        // clap process them automatically
+    }
+
+    let verbose = matches.is_present("verbose");
+
+    if verbose {
+        eprintln!("VEBOSE mode");
+    }
+
     let csize = matches
         .value_of("csize")
         .map(parse_2d_int)
@@ -616,6 +1759,61 @@ pub fn get_args() -> MyResult<Config> {
         .transpose()
         .map_err(|e| format!("illegal tabstop -- {}", e))?;
 
+    let ambiguous = matches
+        .value_of("ambiguous")
+        .map(parse_ambiwidth)
+        .transpose()
+        .map_err(|e| format!("illegal ambiguous -- {}", e))?;
+
+    let normalize = matches
+        .value_of("normalize")
+        .map(parse_normalize)
+        .transpose()
+        .map_err(|e| format!("illegal normalize -- {}", e))?;
+
+    let encoding = matches
+        .value_of("encoding")
+        .map(parse_encoding)
+        .transpose()
+        .map_err(|e| format!("illegal encoding -- {}", e))?;
+
+    let ctrl = matches
+        .value_of("ctrl")
+        .map(parse_ctrlmode)
+        .transpose()
+        .map_err(|e| format!("illegal ctrl -- {}", e))?;
+
+    let mut theme = matches
+        .value_of("theme")
+        .map(parse_theme)
+        .transpose()?
+        .unwrap_or_default();
+    if let Some(fg) = matches.value_of("fg") {
+        theme.fg = parse_rgb(fg).map_err(|e| format!("illegal fg -- {}", e))?;
+    }
+    if let Some(bg) = matches.value_of("bg") {
+        theme.bg = parse_rgb(bg).map_err(|e| format!("illegal bg -- {}", e))?;
+    }
+
+    let wrap = matches
+        .value_of("wrap")
+        .map(parse_wrapmode)
+        .transpose()
+        .map_err(|e| format!("illegal wrap -- {}", e))?;
+
+    let vt = matches
+        .value_of("vt")
+        .map(parse_vtsize)
+        .transpose()
+        .map_err(|e| format!("illegal vt -- {}", e))?;
+
+    let mut widthmap = Vec::new();
+    if let Some(files) = matches.values_of("widthmap") {
+        for f in files {
+            widthmap.extend(load_widthmap(f)?);
+        }
+    }
+
     let sepmargin = matches
         .value_of("sepmargin")
         .map(parse_positive_int)
@@ -660,6 +1858,10 @@ pub fn get_args() -> MyResult<Config> {
         belowgap: "".to_string(),
         lnooffset: lnooffset.unwrap(),
         lnowidth: lnowidth.unwrap(),
+        ambiguous: ambiguous.unwrap(),
+        widthmap,
+        normalize: normalize.unwrap(),
+        encoding: encoding.unwrap(),
         //
         grid: matches.is_present("grid"),
         verbose: matches.is_present("verbose"),
@@ -667,6 +1869,28 @@ pub fn get_args() -> MyResult<Config> {
         numbering: matches.is_present("numbering"),
         standalone: matches.is_present("standalone"),
         pagebreaking: matches.is_present("pagebreaking"),
+        ambreport: matches.is_present("ambreport"),
+        croverwrite: matches.is_present("croverwrite"),
+        overstrike: matches.is_present("overstrike"),
+        formfeed: matches.is_present("formfeed") || matches.is_present("ffnewpage"),
+        ffnewpage: matches.is_present("ffnewpage"),
+        ctrl: ctrl.unwrap(),
+        wrap: wrap.unwrap(),
+        kinsoku: matches.is_present("kinsoku") || matches.is_present("hanging"),
+        hanging: matches.is_present("hanging"),
+        ansi: matches.is_present("ansi") || matches.is_present("mono"),
+        escwarn: matches.is_present("escwarn"),
+        theme,
+        mono: matches.is_present("mono"),
+        fill: matches.is_present("fill"),
+        colors: ["ansi", "mono", "fill", "fg", "bg"]
+            .iter()
+            .any(|name| matches.is_present(name)),
+        boxdraw: matches.is_present("boxdraw"),
+        blockdraw: matches.is_present("blockdraw"),
+        hyperlink: matches.is_present("hyperlink") || matches.is_present("autolink"),
+        autolink: matches.is_present("autolink"),
+        vt,
     };
 
     if !param.gridpitch.is_empty() {
@@ -755,6 +1979,273 @@ struct Geo {
     cvhmin: isize,
 }
 
+fn tex_ascii(ch: &str) -> String {
+    let cmdchars = r"#$%&^_{}\\~";
+    if cmdchars.contains(ch) {
+        match ch {
+            "~" => "\\textasciitilde".to_string(),
+            "^" => "\\textasciicircum".to_string(),
+            "\\" => "\\textbackslash".to_string(),
+            _ => format!("\\{}", ch),
+        }
+    } else {
+        ch.to_string()
+    }
+}
+
+/// xcolor name of a colour, with its definition for those out of
+/// the palette.
+fn color_spec(c: &Color, fg: bool) -> (String, String) {
+    let define = |r: u8, g: u8, b: u8| {
+        (
+            format!("\\definecolor{{fwx}}{{RGB}}{{{},{},{}}}", r, g, b),
+            "fwx".to_string(),
+        )
+    };
+    match c {
+        Color::Default => {
+            let name = if fg { "fwfg" } else { "fwbg" };
+            ("".to_string(), name.to_string())
+        }
+        Color::Index(n) if *n < 16 => ("".to_string(), format!("fwc{}", n)),
+        Color::Index(n) => {
+            let (r, g, b) = xterm256_rgb(*n);
+            define(r, g, b)
+        }
+        Color::Rgb(r, g, b) => define(*r, *g, *b),
+    }
+}
+
+/// colour command of glyphs and rules; reverse video swaps
+/// foreground and background, faint mixes it with the background.
+fn fg_cmd(style: &Style) -> Option<String> {
+    if !style.reverse && !style.faint && style.fg == Color::Default {
+        return None;
+    }
+    let (def, name) = if style.reverse {
+        color_spec(&style.bg, false)
+    } else {
+        color_spec(&style.fg, true)
+    };
+    if style.faint {
+        Some(format!("{}\\color{{{}!50!fwbg}}", def, name))
+    } else {
+        Some(format!("{}\\color{{{}}}", def, name))
+    }
+}
+
+/// colour command of cell background.
+fn bg_cmd(style: &Style) -> Option<String> {
+    if !style.reverse && style.bg == Color::Default {
+        return None;
+    }
+    let (def, name) = if style.reverse {
+        color_spec(&style.fg, true)
+    } else {
+        color_spec(&style.bg, false)
+    };
+    Some(format!("{}\\color{{{}}}", def, name))
+}
+
+fn styled(och: &str, style: &Style) -> String {
+    if och.is_empty() {
+        return och.to_string();
+    }
+    let mut pre = String::new();
+    if style.bold {
+        pre.push_str("\\bfseries");
+    }
+    if style.italic {
+        pre.push_str("\\itshape");
+    }
+    if let Some(cmd) = fg_cmd(style) {
+        pre.push_str(&cmd);
+    }
+    /* a space only after a control word, or it would be typeset */
+    if pre.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        pre.push(' ');
+    }
+    format!("{}{}", pre, och)
+}
+
+/// ANSI colour 0..7 nearest to a colour; bright ones are folded.
+fn color_class(c: &Color) -> Option<usize> {
+    let class = |(r, g, b): (u8, u8, u8)| {
+        (r > 127) as usize | ((g > 127) as usize) << 1 | ((b > 127) as usize) << 2
+    };
+    match c {
+        Color::Default => None,
+        Color::Index(n) if *n < 16 => Some(*n as usize % 8),
+        Color::Index(n) => Some(class(xterm256_rgb(*n))),
+        Color::Rgb(r, g, b) => Some(class((*r, *g, *b))),
+    }
+}
+
+/// style for --mono; foreground colours become weight, italic and
+/// underline, and reverse video becomes a background.
+fn mono_style(style: &Style) -> Style {
+    let (fg, bg) = if style.reverse {
+        let bg = match style.fg {
+            Color::Default => Color::Index(0),
+            fg => fg,
+        };
+        (style.bg, bg)
+    } else {
+        (style.fg, style.bg)
+    };
+    let (bold, italic, underline) = match color_class(&fg) {
+        Some(1) => (true, false, false),
+        Some(2) => (false, false, true),
+        Some(3) => (true, true, false),
+        Some(4) => (false, true, false),
+        Some(5) => (true, false, true),
+        Some(6) => (false, true, true),
+        _ => (false, false, false),
+    };
+    Style {
+        bold: style.bold || bold,
+        italic: style.italic || italic,
+        underline: style.underline || underline,
+        reverse: false,
+        fg: Color::Default,
+        bg,
+        ..style.clone()
+    }
+}
+
+/// background in --mono; hatching for red and green, which diff(1)
+/// uses, and grey levels for the others.  the hatching is made of
+/// horizontal or vertical rules, which the picture environment draws
+/// at any length, unlike sloped lines.
+fn print_monofill(gx: isize, gy: isize, ncells: isize, bg: &Color, param: &Param) {
+    const GREY: [f64; 8] = [0.7, 0.0, 0.0, 0.9, 0.75, 0.8, 0.85, 0.95];
+    let d = param.csize.width as f64 / 2.0;
+    let w = ncells * param.csize.width as isize;
+    let n = (w as f64 / d).floor();
+    let m = (param.lheight as f64 / d).floor();
+    match color_class(bg) {
+        None => {}
+        Some(1) => println!(
+            " \\multiput({},{})(0,{}){{{}}}{{\\rule{{{}pt}}{{0.4pt}}}}",
+            gx, gy, d, m, w
+        ),
+        Some(2) => println!(
+            " \\multiput({},{})({},0){{{}}}{{\\rule{{0.4pt}}{{{}pt}}}}",
+            gx, gy, d, n, param.lheight
+        ),
+        Some(c) => {
+            let cmd = format!("\\color[gray]{{{}}}", GREY[c]);
+            print_bgfill(gx, gy, ncells, &cmd, param);
+        }
+    }
+}
+
+/// grey which stands out from the background by its luminance, for
+/// frames, grid and line numbers on --fill.
+fn contrast_rgb(bg: (u8, u8, u8)) -> (u8, u8, u8) {
+    let (r, g, b) = bg;
+    let luma = 0.2126 * r as f64 + 0.7152 * g as f64 + 0.0722 * b as f64;
+    let target = if luma < 128.0 { 255.0 } else { 0.0 };
+    let mix = |v: u8| (v as f64 + (target - v as f64) * 0.6).round() as u8;
+    (mix(r), mix(g), mix(b))
+}
+
+/// fill cells by background colour.
+fn print_bgfill(gx: isize, gy: isize, ncells: isize, cmd: &str, param: &Param) {
+    println!(
+        " \\put({},{}){{{}\\rule{{{}pt}}{{{}pt}}}}",
+        gx,
+        gy,
+        cmd,
+        ncells * param.csize.width as isize,
+        param.lheight
+    );
+}
+
+/// TeX form of URI for \\href; characters which hyperref cannot take
+/// are percent-encoded.
+fn tex_uri(uri: &str) -> String {
+    let mut s = String::new();
+    for c in uri.chars() {
+        match c {
+            '#' | '%' => {
+                s.push('\\');
+                s.push(c);
+            }
+            '\\' | '{' | '}' | '^' | '~' | ' ' => s.push_str(&format!("\\%{:02X}", c as u32)),
+            _ => s.push(c),
+        }
+    }
+    s
+}
+
+/// glyph of a double-width or double-height line over `ncells`
+/// cells.  a top half spans the row below as well.
+fn scaled_cmd(
+    gx: isize,
+    gy: isize,
+    ncells: isize,
+    och: &str,
+    size: LineSize,
+    param: &Param,
+) -> String {
+    let w = ncells * param.csize.width as isize;
+    if size == LineSize::Top {
+        format!(
+            " \\put({},{}){{\\makebox({},{}){{\\scalebox{{2}}{{\\VV\\mbox{{{}}}}}}}}}",
+            gx,
+            gy - param.lheight as isize,
+            w,
+            param.lheight + param.csize.height,
+            och
+        )
+    } else {
+        format!(
+            " \\put({},{}){{\\makebox({},{}){{\\VV\\scalebox{{2}}[1]{{\\mbox{{{}}}}}}}}}",
+            gx, gy, w, param.csize.height, och
+        )
+    }
+}
+
+/// size in which the `i`th row of a picture is drawn; a top half
+/// without the bottom after it is only doubled in width, for it
+/// would spill out of the picture.
+fn drawn_size(chunk: &[Row], i: usize) -> LineSize {
+    match chunk[i].size {
+        LineSize::Top if chunk.get(i + 1).map(|r| r.size) != Some(LineSize::Bottom) => {
+            LineSize::Wide
+        }
+        size => size,
+    }
+}
+
+/// invisible link box over `ncells` cells.
+fn print_link(gx: isize, gy: isize, ncells: isize, uri: &str, param: &Param) {
+    println!(
+        " \\put({},{}){{\\href{{{}}}{{\\hbox to {}pt{{\\vrule width 0pt height {}pt\\hss}}}}}}",
+        gx,
+        gy,
+        tex_uri(uri),
+        ncells * param.csize.width as isize,
+        param.lheight
+    );
+}
+
+/// horizontal rule over `ncells` cells; `pos` is the height from
+/// the bottom of the character box in csize.height.
+fn print_hrule(gx: isize, gy: isize, ncells: isize, pos: f64, style: &Style, param: &Param) {
+    let h = param.csize.height as f64;
+    println!(
+        " \\put({},{:.2}){{{}\\rule{{{}pt}}{{{:.2}pt}}}}",
+        gx,
+        gy as f64 + h * pos,
+        fg_cmd(style).unwrap_or_default(),
+        ncells * param.csize.width as isize,
+        (h / 20.0).max(0.4)
+    );
+}
+
+#[allow(clippy::explicit_counter_loop)]
 fn print_picture(
     filename: &str,
     chunk: RowChunk,
@@ -765,7 +2256,6 @@ fn print_picture(
 ) {
     let verbose = param.verbose;
 
-    let cmdchars = r"#$%&^_{}\\~";
     let cvheight: isize = (chunk.len() * param.lheight + param.inmargin * 2) as isize;
 
     let mut geo: Geo = parent_geo.clone();
@@ -807,6 +2297,29 @@ fn print_picture(
     );
     println!("\\def\\zsp{{▲}}");
 
+    if param.hyperlink {
+        println!("%% you should use \\usepackage{{hyperref}}");
+    }
+    if chunk.iter().any(|r| r.size != LineSize::Single) {
+        println!("%% you should use \\usepackage{{graphicx}}");
+    }
+
+    if param.colors {
+        println!("%% you should use \\usepackage{{xcolor}}");
+        let theme = &param.theme;
+        let (r, g, b) = theme.fg;
+        println!("\\definecolor{{fwfg}}{{RGB}}{{{},{},{}}}%", r, g, b);
+        let (r, g, b) = theme.bg;
+        println!("\\definecolor{{fwbg}}{{RGB}}{{{},{},{}}}%", r, g, b);
+        for (i, (r, g, b)) in theme.palette.iter().enumerate() {
+            println!("\\definecolor{{fwc{}}}{{RGB}}{{{},{},{}}}%", i, r, g, b);
+        }
+    }
+    if param.fill {
+        let (r, g, b) = contrast_rgb(param.theme.bg);
+        println!("\\definecolor{{fwln}}{{RGB}}{{{},{},{}}}%", r, g, b);
+    }
+
     println!("\\def\\VV{{\\vrule width 0pt height 0.90em depth .25em}}%");
     if param.braise == 0.0 {
         println!(
@@ -835,6 +2348,18 @@ fn print_picture(
         geo.cvwidth, gheight
     );
 
+    if param.fill {
+        /* line numbers are on the fill as well */
+        println!("% fill");
+        println!(
+            " \\put(0,{}){{\\color{{fwbg}}\\rule{{{}pt}}{{{}pt}}}}",
+            gheight - geo.cvheight,
+            geo.txoffset + geo.txwidth,
+            geo.cvheight
+        );
+        println!("\\color{{fwln}}%");
+    }
+
     println!("% frame");
 
     println!("\\thicklines");
@@ -911,12 +2436,24 @@ fn print_picture(
         println!("\\thinlines");
     }
 
+    if param.fill {
+        println!("\\color{{fwfg}}%");
+    }
+
     println!("% body");
 
+    let numcolor = if param.fill { "\\color{fwln}" } else { "" };
+
     let mut gline = 1;
     let mut gx: isize;
     let mut gy: isize;
+    let mut prevsize = LineSize::Single;
+    let sizes: Vec<LineSize> = (0..chunk.len()).map(|i| drawn_size(&chunk, i)).collect();
     for r in chunk {
+        /* the bottom half of double-height was drawn with the top */
+        let size = sizes[gline - 1];
+        let hidden = size == LineSize::Bottom && prevsize == LineSize::Top;
+        prevsize = size;
         //        gy = cvheight - (param.lheight * gline) as isize - param.inmargin as isize;
         gy = gheight - (param.lheight * gline) as isize - param.inmargin as isize;
         /*
@@ -932,41 +2469,80 @@ fn print_picture(
             for (c, ch) in numstr.chars().enumerate() {
                 gx = (param.inmargin + param.numcsize.width * c) as isize;
                 if ch != ' ' {
-                    println!("{{\\numfont\\FA{{{}}}{{{}}}{{{}}}}}", gx, gy, ch);
+                    println!(
+                        "{{\\numfont{}\\FA{{{}}}{{{}}}{{{}}}}}",
+                        numcolor, gx, gy, ch
+                    );
                 }
             }
         }
 
         gx = geo.txoffset + param.inmargin as isize;
-        for tk in r.tokens {
+        let mut linkrun: Option<(String, isize, isize)> = None;
+        for mut tk in r.tokens {
+            if size != LineSize::Single {
+                tk.width *= 2;
+            }
+            if param.mono {
+                tk.style = mono_style(&tk.style);
+            }
+            /* adjacent cells of a link share a box */
+            if param.hyperlink && tk.width > 0 {
+                match (&mut linkrun, &tk.style.link) {
+                    (Some((uri, _, n)), Some(l)) if uri == l => *n += tk.width,
+                    (_, l) => {
+                        if let Some((uri, x, n)) = linkrun.take() {
+                            print_link(x, gy, n, &uri, param);
+                        }
+                        linkrun = l.clone().map(|u| (u, gx, tk.width));
+                    }
+                }
+            }
+            /* cursor movements paint no cell */
+            let painted = !matches!(
+                tk.kind,
+                TokenKind::Escape(_) | TokenKind::Tab | TokenKind::Skip
+            );
+            if painted && param.mono {
+                print_monofill(gx, gy, tk.width, &tk.style.bg, param);
+            } else if painted && let Some(cmd) = bg_cmd(&tk.style) {
+                print_bgfill(gx, gy, tk.width, &cmd, param);
+            }
             match tk.kind {
+                TokenKind::Ascii(ch) | TokenKind::Misc(ch)
+                    if size != LineSize::Single && !hidden && ch != " " =>
+                {
+                    let text = if ch.is_ascii() { tex_ascii(&ch) } else { ch };
+                    let och = styled(&text, &tk.style);
+                    println!("{}", scaled_cmd(gx, gy, tk.width, &och, size, param));
+                }
+                TokenKind::Ascii(_) | TokenKind::Misc(_) if size != LineSize::Single => {}
                 TokenKind::Ascii(ch) => {
                     let mut och: String = "".to_string();
-                    if let Some(_x) = cmdchars.find(&ch) {
-                        if ch == "~" {
-                            och.push_str("\\textasciitilde");
-                        } else if ch == "^" {
-                            och.push_str("\\textasciicircum");
-                        } else if ch == "\\" {
-                            och.push_str("\\textbackslash");
-                        } else {
-                            och.push('\\');
-                            och.push_str(&ch);
-                        }
-                    } else if ch == " " {
+                    if ch == " " {
                         if param.spcmarking {
                             //                            println!(" \\FA{{{}}}{{{}}}{{\\hsp}}", gx, gy - param.braise);
                             println!(" \\FA{{{}}}{{{}}}{{\\hsp}}", gx, gy);
                         }
                     } else {
-                        och.push_str(&ch);
+                        och.push_str(&tex_ascii(&ch));
                     }
 
                     //                  println!(" \\FA{{{}}}{{{}}}{{{}}}", gx, gy - param.braise, och);
-                    println!(" \\FA{{{}}}{{{}}}{{{}}}", gx, gy, och);
+                    println!(" \\FA{{{}}}{{{}}}{{{}}}", gx, gy, styled(&och, &tk.style));
                 }
                 TokenKind::Misc(ch) => {
-                    if param.spcmarking && ch == "　" {
+                    let mut cs = ch.chars();
+                    let single = cs.next().filter(|_| cs.next().is_none());
+                    let drawn = single.is_some_and(|c| {
+                        (param.boxdraw
+                            && draw::print_boxdraw(c, gx, gy, tk.width, &tk.style, param))
+                            || (param.blockdraw
+                                && draw::print_blockdraw(c, gx, gy, tk.width, &tk.style, param))
+                    });
+                    if drawn {
+                        /* drawn by lines instead of the glyph */
+                    } else if param.spcmarking && ch == "　" {
                         println!(
                             " \\FX{{{}}}{{{}}}{{\\zsp}}",
                             gx + (param.csize.width as isize) / 2,
@@ -976,13 +2552,40 @@ fn print_picture(
                         println!(
                             " \\FX{{{:1}}}{{{}}}{{{}}}",
                             //                            gx + (param.csize.width as isize) / 2,
-                            gx as f32 + (param.csize.width as f32) / 2.0,
+                            gx as f32 + ((tk.width - 1) * param.csize.width as isize) as f32 / 2.0,
                             gy,
-                            ch
+                            styled(&ch, &tk.style)
                         );
                     }
                 }
+                TokenKind::Ctrl(text) => {
+                    if text.is_ascii() {
+                        for (i, ch) in text.chars().enumerate() {
+                            println!(
+                                " \\FA{{{}}}{{{}}}{{{}}}",
+                                gx + (i * param.csize.width) as isize,
+                                gy,
+                                tex_ascii(&ch.to_string())
+                            );
+                        }
+                    } else {
+                        println!(
+                            " \\FX{{{:1}}}{{{}}}{{{}}}",
+                            gx as f32 + ((tk.width - 1) * param.csize.width as isize) as f32 / 2.0,
+                            gy,
+                            text
+                        );
+                    }
+                    println!(
+                        " \\put({},{}){{\\dashbox{{1}}({},{}){{}}}}",
+                        gx,
+                        gy,
+                        tk.width * param.csize.width as isize,
+                        param.csize.height
+                    );
+                }
                 TokenKind::Escape(_) => {}
+                TokenKind::Tab => {}
                 TokenKind::Skip => {}
                 TokenKind::Hole(label) => {
                     println!(
@@ -995,8 +2598,27 @@ fn print_picture(
                 }
                 _ => {}
             }
+            let st = &tk.style;
+            if painted {
+                if st.underline {
+                    print_hrule(gx, gy, tk.width, 0.075, st, param);
+                }
+                if st.dunderline {
+                    print_hrule(gx, gy, tk.width, 0.0, st, param);
+                    print_hrule(gx, gy, tk.width, 0.1, st, param);
+                }
+                if st.strike {
+                    print_hrule(gx, gy, tk.width, 0.42, st, param);
+                }
+                if st.overline {
+                    print_hrule(gx, gy, tk.width, 0.95, st, param);
+                }
+            }
             gx += tk.width * param.csize.width as isize;
         }
+        if let Some((uri, x, n)) = linkrun {
+            print_link(x, gy, n, &uri, param);
+        }
 
         if r.setret {
             println!(
@@ -1039,8 +2661,128 @@ fn print_picture(
     println!("%% end {} {}", filename, lnooffset);
 }
 
+/// guess encoding of legacy Japanese text.  7-bit ISO-2022-JP is
+/// checked before UTF-8 since it is also valid as UTF-8.
+fn guess_encoding(buf: &[u8]) -> &'static Encoding {
+    if let Some((enc, _)) = Encoding::for_bom(buf) {
+        return enc;
+    }
+    let jis_escapes: [&[u8]; 4] = [b"\x1b$@", b"\x1b$B", b"\x1b(J", b"\x1b(I"];
+    if jis_escapes
+        .iter()
+        .any(|esc| buf.windows(esc.len()).any(|w| w == *esc))
+    {
+        return encoding_rs::ISO_2022_JP;
+    }
+    if std::str::from_utf8(buf).is_ok() {
+        return encoding_rs::UTF_8;
+    }
+
+    // EUC-JP decoded as Shift_JIS becomes a run of half-width katakana,
+    // and Latin-1 text as either becomes a run of replacements
+    let score = |s: &str| -> isize {
+        s.chars()
+            .map(|c| match c as u32 {
+                0x3040..=0x30FF | 0x4E00..=0x9FFF => 1,
+                0xFF61..=0xFF9F => -1,
+                0xFFFD => -1,
+                _ => 0,
+            })
+            .sum()
+    };
+    let mut best: (&'static Encoding, isize) = (encoding_rs::WINDOWS_1252, 0);
+    for enc in [encoding_rs::SHIFT_JIS, encoding_rs::EUC_JP] {
+        let (s, _) = enc.decode_without_bom_handling(buf);
+        let sc = score(&s);
+        if sc > best.1 {
+            best = (enc, sc);
+        }
+    }
+    best.0
+}
+
+/// read whole input and decode it into lines.  invalid byte sequences
+/// are replaced by U+FFFD and reported with file name and line number.
+/// the stream is decoded before it is split, for UTF-16 and the shift
+/// state of ISO-2022-JP.
+fn read_lines(filename: &str, fp: &mut dyn BufRead, param: &Param) -> MyResult<Vec<String>> {
+    let mut buf = Vec::new();
+    fp.read_to_end(&mut buf)?;
+
+    let enc = param.encoding.unwrap_or_else(|| guess_encoding(&buf));
+    if param.verbose {
+        eprintln!("{}: encoding {}", filename, enc.name());
+    }
+    let mut body = match Encoding::for_bom(&buf) {
+        Some((bom, len)) if bom == enc => &buf[len..],
+        _ => &buf[..],
+    };
+
+    let mut decoder = enc.new_decoder_without_bom_handling();
+    let mut text = String::new();
+    let (mut lineno, mut counted) = (1, 0);
+    loop {
+        let need = decoder
+            .max_utf8_buffer_length_without_replacement(body.len())
+            .unwrap_or(body.len());
+        text.reserve(need);
+        let (res, read) = decoder.decode_to_string_without_replacement(body, &mut text, true);
+        body = &body[read..];
+        match res {
+            encoding_rs::DecoderResult::InputEmpty => break,
+            encoding_rs::DecoderResult::OutputFull => {}
+            encoding_rs::DecoderResult::Malformed(_, _) => {
+                lineno += text[counted..].matches('\n').count();
+                counted = text.len();
+                eprintln!(
+                    "{}:{}: invalid {} sequence replaced",
+                    filename,
+                    lineno,
+                    enc.name()
+                );
+                text.push('\u{FFFD}');
+            }
+        }
+    }
+
+    let mut lines: Vec<String> = text
+        .split('\n')
+        .map(|l| l.strip_suffix('\r').unwrap_or(l).to_string())
+        .collect();
+    if lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    Ok(lines)
+}
+
+/// whether the picture of `lineperpage` rows ends before `rest`, and
+/// if so, whether a page break follows; only a form feed asks for it.
+fn picture_break(rest: &[Row], lineperpage: usize, param: &Param) -> Option<bool> {
+    let ffbreak = rest.first().is_some_and(|r| r.ffbreak);
+    if lineperpage == 0 || !(ffbreak || splits_pair(rest, lineperpage, param.lmax)) {
+        return None;
+    }
+    Some(ffbreak && param.ffnewpage && !param.pagebreaking)
+}
+
+/// whether the picture of `lineperpage` rows has no room for the
+/// double-height pair at the head of `rest`; the top half is drawn
+/// over the bottom row, so they go to a picture together.
+fn splits_pair(rest: &[Row], lineperpage: usize, lmax: usize) -> bool {
+    let pair = matches!(
+        rest,
+        [top, bottom, ..] if top.size == LineSize::Top && bottom.size == LineSize::Bottom
+    );
+    pair && lineperpage + 2 > lmax
+}
+
 #[allow(clippy::too_many_arguments)]
-fn fwtype(filename: &str, fp: &mut dyn BufRead, param: &Param) {
+fn fwtype(
+    filename: &str,
+    fp: &mut dyn BufRead,
+    param: &Param,
+    ambseen: &mut BTreeMap<char, usize>,
+) -> MyResult<()> {
     let mut maxwidth = 0;
     let verbose = param.verbose;
 
@@ -1074,31 +2816,102 @@ fn fwtype(filename: &str, fp: &mut dyn BufRead, param: &Param) {
 
     let mut cline: isize = 0;
     let mut crow: isize = 0;
-
-    for line_result in fp.lines() {
-        let line = line_result.unwrap();
-        /*
-        eprintln!("; line |{}|", line);
-        */
-
-        let chunk = parse_line(&line, param.tabstop as isize, param.wmax as isize);
-        /*
-        eprintln!("; {} chunk {:?}", _line_num, chunk);
-        */
-        cline += 1;
-        for (r_per_i, mut x) in chunk.into_iter().enumerate() {
+    let mut ffpending = false;
+    let mut sgr = Style::default();
+    let mut escseen: BTreeMap<String, usize> = BTreeMap::new();
+
+    let lines = read_lines(filename, fp, param)?;
+    if let Some((cols, rows)) = param.vt {
+        /* play the whole stream on a screen; its rows are the result */
+        let text: Vec<String> = lines
+            .into_iter()
+            .map(|line| normalize_line(line, param.normalize))
+            .collect();
+        let mut screen = vt::Screen::new(cols, rows);
+        screen.feed(&text.join("\n"), param);
+        if param.escwarn {
+            for (seq, n) in screen.unknown_escapes() {
+                *escseen.entry(seq.clone()).or_insert(0) += n;
+            }
+        }
+        for mut x in screen.rows(param) {
+            if param.autolink {
+                autolink(&mut x.tokens);
+            }
             if x.width > maxwidth {
                 maxwidth = x.width;
             }
-            if r_per_i == 0 {
-                x.lineno = cline;
-            }
             fullrow.push(x);
             crow += 1;
         }
+        cline = rows as isize;
+    } else {
+        for line in lines {
+            let line = normalize_line(line, param.normalize);
+            /*
+            eprintln!("; line |{}|", line);
+            */
+
+            /* form feed splits the line; the later part starts a new picture */
+            let segments: Vec<&str> = if param.formfeed {
+                line.split('\x0c').collect()
+            } else {
+                vec![&line]
+            };
+
+            cline += 1;
+            let mut numbered = false;
+            for (i, seg) in segments.iter().enumerate() {
+                if i > 0 {
+                    ffpending = true;
+                }
+                if seg.is_empty() && segments.len() > 1 {
+                    continue;
+                }
+
+                let chunk = parse_line(seg, &mut sgr, param);
+                if param.ambreport {
+                    count_ambiguous(&chunk, ambseen);
+                }
+                if param.escwarn {
+                    for tk in chunk.iter().flat_map(|r| r.tokens.iter()) {
+                        if let TokenKind::Escape(seq) = &tk.kind
+                            && !is_known_escape(seq)
+                        {
+                            *escseen.entry(seq.clone()).or_insert(0) += 1;
+                        }
+                    }
+                }
+                /*
+                eprintln!("; {} chunk {:?}", _line_num, chunk);
+                */
+                for mut x in chunk.into_iter() {
+                    if x.width > maxwidth {
+                        maxwidth = x.width;
+                    }
+                    if !numbered {
+                        x.lineno = cline;
+                        numbered = true;
+                    }
+                    if ffpending {
+                        x.ffbreak = true;
+                        ffpending = false;
+                    }
+                    fullrow.push(x);
+                    crow += 1;
+                }
+            }
+        }
     }
     geo.nchars = maxwidth;
 
+    if !escseen.is_empty() {
+        eprintln!("{}: unknown escape sequences dropped", filename);
+        for (seq, n) in &escseen {
+            eprintln!("  {:?} {}", seq, n);
+        }
+    }
+
     /*
         view_chunk("full", &fullrow);
     */
@@ -1179,6 +2992,23 @@ fn fwtype(filename: &str, fp: &mut dyn BufRead, param: &Param) {
             break;
         }
 
+        if let Some(newpage) = picture_break(&fullrow, lineperpage, param) {
+            if verbose {
+                eprintln!("call pagepring picno# {} {} lines", picno, lineperpage);
+            }
+            print_picture(filename, curpic.clone(), lineoffset, crow, &geo, param);
+            if newpage {
+                println!("\\newpage");
+            }
+
+            curpic.clear();
+
+            lineoffset += lineperpage;
+
+            picno += 1;
+            lineperpage = 0;
+        }
+
         curpic.push(fullrow.remove(0));
         lineperpage += 1;
 
@@ -1203,6 +3033,8 @@ fn fwtype(filename: &str, fp: &mut dyn BufRead, param: &Param) {
 
         // lineoffset += lineperpage;
     }
+
+    Ok(())
 }
 
 pub fn run(config: Config) -> MyResult<()> {
@@ -1214,20 +3046,48 @@ pub fn run(config: Config) -> MyResult<()> {
     if param.standalone {
         println!("\\documentclass{{article}} %%% fwtype-opt");
         println!("\\usepackage[T1]{{fontenc}} %%% fwtype-opt");
+        if param.colors {
+            println!("\\usepackage{{xcolor}} %%% fwtype-opt");
+        }
+        if param.vt.is_some() {
+            println!("\\usepackage{{graphicx}} %%% fwtype-opt");
+        }
+        if param.hyperlink {
+            println!("\\usepackage{{hyperref}} %%% fwtype-opt");
+        }
         //        println!("\\usepackage{{times}} %%% fwtypw-opt");
         println!("\\begin{{document}} %%% fwtypw-opt");
         println!("\\par %%% fwtypw-opt");
     }
 
+    let mut ambseen: BTreeMap<char, usize> = BTreeMap::new();
+
     for filename in config.files.iter() {
         match open(filename) {
             Err(err) => eprintln!("{}: {}", filename, err),
             Ok(mut file) => {
-                fwtype(filename, &mut file, &param);
+                if let Err(err) = fwtype(filename, &mut file, &param, &mut ambseen) {
+                    eprintln!("{}: {}", filename, err);
+                }
             }
         }
     }
 
+    if param.ambreport {
+        let mode = match param.ambiguous {
+            AmbiWidth::Wide => "wide",
+            AmbiWidth::Narrow => "narrow",
+        };
+        eprintln!(
+            "ambiguous characters: {} kinds (treated as {})",
+            ambseen.len(),
+            mode
+        );
+        for (c, n) in &ambseen {
+            eprintln!("  U+{:04X} {} {}", *c as u32, c, n);
+        }
+    }
+
     if param.standalone {
         println!("\\end{{document}} %%% fwtypw-opt");
     }
@@ -1268,6 +3128,162 @@ fn parse_2d_int(val: &str) -> MyResult<CharSize> {
     })
 }
 
+/*
+ * width override file:
+ *      # Powerline glyphs
+ *      U+E0A0..U+E0B3  1
+ *      1F300-1F5FF     2
+ */
+fn load_widthmap(filename: &str) -> MyResult<Vec<(u32, u32, isize)>> {
+    let mut map = Vec::new();
+    let fp = BufReader::new(File::open(filename).map_err(|e| format!("{}: {}", filename, e))?);
+    for (i, line_result) in fp.lines().enumerate() {
+        let line = line_result?;
+        let body = line.split('#').next().unwrap_or("").trim();
+        if body.is_empty() {
+            continue;
+        }
+        let entry = parse_widthmap_entry(body)
+            .map_err(|e| format!("{}:{}: illegal width map -- {}", filename, i + 1, e))?;
+        map.push(entry);
+    }
+    Ok(map)
+}
+
+/// bundled theme by name, or a theme file of lines as "color1 #cd0000",
+/// "foreground #000000" and "background #ffffff".  colours not given
+/// are of xterm.
+fn parse_theme(val: &str) -> MyResult<Theme> {
+    if let Some((_, theme)) = THEMES.iter().find(|(name, _)| *name == val) {
+        return Ok(theme.clone());
+    }
+    let mut theme = Theme::default();
+    let fp =
+        BufReader::new(File::open(val).map_err(|e| format!("illegal theme -- {}: {}", val, e))?);
+    for (i, line_result) in fp.lines().enumerate() {
+        let line = line_result?;
+        let body = line.trim();
+        if body.is_empty() || body.starts_with('#') {
+            continue;
+        }
+        parse_theme_entry(body, &mut theme)
+            .map_err(|e| format!("{}:{}: illegal theme -- {}", val, i + 1, e))?;
+    }
+    Ok(theme)
+}
+
+fn parse_theme_entry(val: &str, theme: &mut Theme) -> MyResult<()> {
+    let mut parts = val.split_whitespace();
+    let (Some(key), Some(rgb), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(From::from(val));
+    };
+    let rgb = parse_rgb(rgb)?;
+    match key.trim_end_matches([':', '=']) {
+        "foreground" => theme.fg = rgb,
+        "background" => theme.bg = rgb,
+        k => match k.strip_prefix("color").map(str::parse::<usize>) {
+            Some(Ok(n)) if n < 16 => theme.palette[n] = rgb,
+            _ => return Err(From::from(key)),
+        },
+    }
+    Ok(())
+}
+
+fn parse_rgb(val: &str) -> MyResult<(u8, u8, u8)> {
+    let hex = val
+        .strip_prefix('#')
+        .filter(|h| h.len() == 6 && h.chars().all(|c| c.is_ascii_hexdigit()));
+    let Some(n) = hex.and_then(|h| u32::from_str_radix(h, 16).ok()) else {
+        return Err(From::from(val));
+    };
+    Ok(((n >> 16) as u8, (n >> 8) as u8, n as u8))
+}
+
+fn parse_widthmap_entry(val: &str) -> MyResult<(u32, u32, isize)> {
+    let parts: Vec<&str> = val.split_whitespace().collect();
+    if parts.len() != 2 {
+        return Err(From::from(val));
+    }
+    let (lo, hi) = match parts[0]
+        .split_once("..")
+        .or_else(|| parts[0].split_once('-'))
+    {
+        Some((a, b)) => (parse_codepoint(a)?, parse_codepoint(b)?),
+        None => {
+            let a = parse_codepoint(parts[0])?;
+            (a, a)
+        }
+    };
+    let w = parse_uint(parts[1])?;
+    if lo > hi || w > 2 {
+        return Err(From::from(val));
+    }
+    Ok((lo, hi, w as isize))
+}
+
+fn parse_codepoint(val: &str) -> MyResult<u32> {
+    let hex = val
+        .strip_prefix("U+")
+        .or_else(|| val.strip_prefix("u+"))
+        .unwrap_or(val);
+    match u32::from_str_radix(hex, 16) {
+        Ok(n) if n <= 0x10FFFF => Ok(n),
+        _ => Err(From::from(val)),
+    }
+}
+
+fn parse_ambiwidth(val: &str) -> MyResult<AmbiWidth> {
+    match val {
+        "narrow" => Ok(AmbiWidth::Narrow),
+        "wide" => Ok(AmbiWidth::Wide),
+        _ => Err(From::from(val)),
+    }
+}
+
+fn parse_encoding(val: &str) -> MyResult<Option<&'static Encoding>> {
+    match val {
+        "auto" => Ok(None),
+        "latin1" => Ok(Some(encoding_rs::WINDOWS_1252)),
+        _ => match Encoding::for_label(val.as_bytes()) {
+            Some(enc) => Ok(Some(enc)),
+            None => Err(From::from(val)),
+        },
+    }
+}
+
+fn parse_vtsize(val: &str) -> MyResult<(usize, usize)> {
+    match val.split_once('x') {
+        Some((c, r)) => Ok((parse_positive_int(c)?, parse_positive_int(r)?)),
+        None => Err(From::from(val)),
+    }
+}
+
+fn parse_wrapmode(val: &str) -> MyResult<WrapMode> {
+    match val {
+        "char" => Ok(WrapMode::Char),
+        "word" => Ok(WrapMode::Word),
+        _ => Err(From::from(val)),
+    }
+}
+
+fn parse_ctrlmode(val: &str) -> MyResult<CtrlMode> {
+    match val {
+        "raw" => Ok(CtrlMode::Raw),
+        "caret" => Ok(CtrlMode::Caret),
+        "picture" => Ok(CtrlMode::Picture),
+        _ => Err(From::from(val)),
+    }
+}
+
+fn parse_normalize(val: &str) -> MyResult<Normalize> {
+    match val {
+        "none" => Ok(Normalize::None),
+        "nfc" => Ok(Normalize::Nfc),
+        "nfkc" => Ok(Normalize::Nfkc),
+        _ => Err(From::from(val)),
+    }
+}
+
 fn parse_positive_int(val: &str) -> MyResult<usize> {
     match val.parse() {
         Ok(n) if n > 0 => Ok(n),
@@ -1282,14 +3298,15 @@ fn parse_qint(val: &str) -> MyResult<isize> {
         _ => Err(From::from(val)),
     }
 }
+*/
 
+#[cfg(test)]
 fn parse_int(val: &str) -> MyResult<isize> {
     match val.parse() {
         Ok(n) => Ok(n),
         _ => Err(From::from(val)),
     }
 }
-*/
 
 fn parse_uint(val: &str) -> MyResult<usize> {
     match val.parse() {
@@ -1299,6 +3316,24 @@ fn parse_uint(val: &str) -> MyResult<usize> {
 }
 
 // --------------------------------------------------
+/// characters of rows for tests; a skipped cell is a space, and other
+/// tokens are dropped.
+#[cfg(test)]
+fn row_texts(rows: &[Row]) -> Vec<String> {
+    rows.iter()
+        .map(|r| {
+            r.tokens
+                .iter()
+                .map(|tk| match &tk.kind {
+                    TokenKind::Ascii(s) | TokenKind::Misc(s) => s.as_str(),
+                    TokenKind::Skip => " ",
+                    _ => "",
+                })
+                .collect()
+        })
+        .collect()
+}
+
 #[test]
 fn test_parse_int() {
     // -3 is an OK integer
@@ -1366,3 +3401,689 @@ fn test_parse_2d_int() {
         }
     );
 }
+
+#[test]
+fn test_east_asian_width() {
+    assert_eq!(east_asian_width('A'), EastAsianWidth::Narrow);
+    assert_eq!(east_asian_width('漢'), EastAsianWidth::Wide);
+    assert_eq!(east_asian_width('Ａ'), EastAsianWidth::Fullwidth);
+    assert_eq!(east_asian_width('ｱ'), EastAsianWidth::Halfwidth);
+    assert_eq!(east_asian_width('○'), EastAsianWidth::Ambiguous);
+    assert_eq!(east_asian_width('ç'), EastAsianWidth::Neutral);
+    assert_eq!(east_asian_width('Ж'), EastAsianWidth::Ambiguous);
+}
+
+#[test]
+fn test_parse_line_width() {
+    // half-width katakana and Latin-1 letters occupy a cell each
+    let rows = parse_line("ｱｲｳ garçon 漢字", &mut Style::default(), &Param::default());
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].width, 3 + 1 + 6 + 1 + 4);
+}
+
+#[test]
+fn test_ambiguous_width() {
+    let mut param = Param::default();
+    let rows = parse_line("○→§", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 3);
+    let rows = parse_line("café αя", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 7);
+
+    let mut seen = BTreeMap::new();
+    count_ambiguous(&rows, &mut seen);
+    assert_eq!(seen.len(), 3);
+    assert_eq!(seen.get(&'é'), Some(&1));
+
+    param.ambiguous = AmbiWidth::Wide;
+    let rows = parse_line("○→§", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 6);
+    let rows = parse_line("café αя", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 10);
+}
+
+#[test]
+fn test_parse_widthmap_entry() {
+    let res = parse_widthmap_entry("U+E0A0..U+E0B3 1");
+    assert_eq!(res.unwrap(), (0xE0A0, 0xE0B3, 1));
+
+    let res = parse_widthmap_entry("1F600-1F64F 2");
+    assert_eq!(res.unwrap(), (0x1F600, 0x1F64F, 2));
+
+    let res = parse_widthmap_entry("200B 0");
+    assert_eq!(res.unwrap(), (0x200B, 0x200B, 0));
+
+    // width must be 0, 1 or 2
+    assert!(parse_widthmap_entry("E0A0 3").is_err());
+    assert!(parse_widthmap_entry("E0B3..E0A0 1").is_err());
+    assert!(parse_widthmap_entry("foo 1").is_err());
+
+    let mut param = Param::default();
+    param.widthmap.push((0xE0A0, 0xE0B3, 1));
+    param.widthmap.push((0x25CB, 0x25CB, 0));
+    let rows = parse_line("\u{E0B0}○漢", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 1 + 2);
+
+    // ASCII is looked up as well
+    param.widthmap.push(('*' as u32, '*' as u32, 2));
+    let rows = parse_line("a*b", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 1 + 2 + 1);
+    assert_eq!(rows[0].tokens[1].width, 2);
+}
+
+#[test]
+fn test_grapheme_width() {
+    let param = Param::default();
+    // combining acute accent on e
+    assert_eq!(grapheme_width("e\u{301}", &param), 1);
+    // zero width space, joiner and non-joiner emit no cell
+    assert_eq!(grapheme_width("\u{200B}", &param), 0);
+    assert_eq!(grapheme_width("\u{200D}", &param), 0);
+    assert_eq!(grapheme_width("\u{200C}", &param), 0);
+    // family emoji joined by ZWJ
+    assert_eq!(
+        grapheme_width("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}", &param),
+        2
+    );
+    // variation selectors
+    assert_eq!(grapheme_width("\u{2764}\u{FE0F}", &param), 2);
+    assert_eq!(grapheme_width("\u{231A}\u{FE0E}", &param), 1);
+    // halfwidth sound marks extend the cluster but take a cell
+    assert_eq!(grapheme_width("\u{FF76}\u{FF9E}", &param), 2);
+    let rows = parse_line("ｶﾞｷﾞ|", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 5);
+    // emoji modifier, flag and conjoining jamo
+    assert_eq!(grapheme_width("\u{1F44D}\u{1F3FD}", &param), 2);
+    assert_eq!(grapheme_width("\u{1F1EF}\u{1F1F5}", &param), 2);
+    assert_eq!(grapheme_width("\u{1100}\u{1161}\u{11A8}", &param), 2);
+
+    let rows = parse_line("a\u{200B}b\u{309A}", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 2);
+    assert_eq!(rows[0].tokens.len(), 2);
+}
+
+#[test]
+fn test_normalize_line() {
+    // decomposed "ガ" as in file names on macOS
+    let nfd = "\u{30AB}\u{3099}".to_string();
+    assert_eq!(normalize_line(nfd.clone(), Normalize::None), nfd);
+    assert_eq!(normalize_line(nfd.clone(), Normalize::Nfc), "\u{30AC}");
+    assert_eq!(normalize_line("ｶﾞ".to_string(), Normalize::Nfc), "ｶﾞ");
+    assert_eq!(normalize_line("ｶﾞ".to_string(), Normalize::Nfkc), "\u{30AC}");
+}
+
+#[test]
+fn test_guess_encoding() {
+    // "漢字" in each encoding
+    assert_eq!(guess_encoding("漢字".as_bytes()), encoding_rs::UTF_8);
+    assert_eq!(guess_encoding(b"\x8a\xbf\x8e\x9a"), encoding_rs::SHIFT_JIS);
+    assert_eq!(guess_encoding(b"\xb4\xc1\xbb\xfa"), encoding_rs::EUC_JP);
+    assert_eq!(
+        guess_encoding(b"\x1b$B4A;z\x1b(B"),
+        encoding_rs::ISO_2022_JP
+    );
+    assert_eq!(guess_encoding(b"caf\xe9"), encoding_rs::WINDOWS_1252);
+}
+
+#[test]
+fn test_read_lines() {
+    let param = Param::default();
+    let mut fp: &[u8] = b"\xb4\xc1\xbb\xfa\xa4\xc8\xa4\xab\xa4\xca\r\nabc\n\xff\n";
+    let lines = read_lines("-", &mut fp, &param).unwrap();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "漢字とかな");
+    assert_eq!(lines[1], "abc");
+    assert_eq!(lines[2], "\u{FFFD}");
+
+    // UTF-16 is decoded before splitting lines
+    let mut fp: &[u8] = b"\xff\xfea\x00\n\x00\x0a\x4e\r\x00\n\x00";
+    let lines = read_lines("-", &mut fp, &param).unwrap();
+    assert_eq!(lines, ["a", "\u{4E0A}"]);
+}
+
+#[test]
+fn test_overwrite_cr() {
+    let mut param = Param {
+        croverwrite: true,
+        ..Default::default()
+    };
+    let rows = parse_line(
+        " 10% [#    ]\r 50% [###  ]\r100% [#####]",
+        &mut Style::default(),
+        &param,
+    );
+    assert_eq!(rows.len(), 1);
+    assert_eq!(row_texts(&rows)[0], "100% [#####]");
+
+    // a shorter line leaves the tail of the longer one
+    let rows = parse_line("downloading\rdone", &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows)[0], "doneloading");
+
+    // half of a wide character is overwritten
+    let rows = parse_line("漢字\r\tx", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 9);
+    let rows = parse_line("漢字\r a", &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows)[0], " a字");
+
+    param.croverwrite = false;
+    let rows = parse_line("ab\rc", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 4);
+}
+
+#[test]
+fn test_overstrike() {
+    let param = Param {
+        overstrike: true,
+        ..Default::default()
+    };
+    let rows = parse_line(
+        "N\x08NA\x08AM\x08ME\x08E  _\x08l_\x08s",
+        &mut Style::default(),
+        &param,
+    );
+    let tks = &rows[0].tokens;
+    assert_eq!(rows[0].width, 8);
+    assert!(tks[0].style.bold && tks[3].style.bold);
+    assert!(!tks[4].style.bold && !tks[4].style.underline);
+    assert!(tks[6].style.underline && tks[7].style.underline);
+    assert!(matches!(&tks[7].kind, TokenKind::Ascii(s) if s == "s"));
+
+    // bold and underlined at once, and a wide character
+    let rows = parse_line("_\x08X\x08X漢\x08\x08漢", &mut Style::default(), &param);
+    let tks = &rows[0].tokens;
+    assert_eq!(rows[0].width, 3);
+    assert!(tks[0].style.bold && tks[0].style.underline);
+    assert!(tks[1].style.bold);
+}
+
+#[test]
+fn test_ctrl_text() {
+    assert_eq!(ctrl_text('\x07', CtrlMode::Raw), None);
+    assert_eq!(ctrl_text('\x07', CtrlMode::Caret).unwrap(), "^G");
+    assert_eq!(ctrl_text('\x00', CtrlMode::Caret).unwrap(), "^@");
+    assert_eq!(ctrl_text('\x7f', CtrlMode::Caret).unwrap(), "^?");
+    assert_eq!(ctrl_text('\u{9b}', CtrlMode::Caret).unwrap(), "M-^[");
+    assert_eq!(ctrl_text('\x07', CtrlMode::Picture).unwrap(), "\u{2407}");
+    assert_eq!(ctrl_text('a', CtrlMode::Caret), None);
+
+    let param = Param {
+        ctrl: CtrlMode::Caret,
+        ..Default::default()
+    };
+    let rows = parse_line("a\x07b\x1f\tc", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 1 + 2 + 1 + 2 + 2 + 1);
+
+    // a control picture is a character of a cell
+    let param = Param {
+        ctrl: CtrlMode::Picture,
+        ..Default::default()
+    };
+    let rows = parse_line("a\x07b", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 3);
+    assert_eq!(rows[0].tokens[1].width, 1);
+}
+
+#[test]
+fn test_apply_sgr() {
+    let mut st = Style::default();
+    apply_sgr(&mut st, "31;44");
+    assert_eq!((st.fg, st.bg), (Color::Index(1), Color::Index(4)));
+    apply_sgr(&mut st, "92;107");
+    assert_eq!((st.fg, st.bg), (Color::Index(10), Color::Index(15)));
+    apply_sgr(&mut st, "38;5;208;48;2;1;2;3");
+    assert_eq!((st.fg, st.bg), (Color::Index(208), Color::Rgb(1, 2, 3)));
+    apply_sgr(&mut st, "38:2::10:20:30");
+    assert_eq!(st.fg, Color::Rgb(10, 20, 30));
+    apply_sgr(&mut st, "39");
+    assert_eq!((st.fg, st.bg), (Color::Default, Color::Rgb(1, 2, 3)));
+    apply_sgr(&mut st, "");
+    assert_eq!(st, Style::default());
+
+    assert_eq!(xterm256_rgb(196), (255, 0, 0));
+    assert_eq!(xterm256_rgb(244), (128, 128, 128));
+
+    // the state lasts over lines
+    let param = Param {
+        ansi: true,
+        ..Default::default()
+    };
+    let mut sgr = Style::default();
+    let rows = parse_line("\x1b[32mok", &mut sgr, &param);
+    assert_eq!(rows[0].tokens[1].style.fg, Color::Index(2));
+    let rows = parse_line("go\x1b[0m", &mut sgr, &param);
+    assert_eq!(rows[0].tokens[0].style.fg, Color::Index(2));
+    assert_eq!(sgr.fg, Color::Default);
+}
+
+#[test]
+fn test_sgr_attributes() {
+    let mut st = Style::default();
+    apply_sgr(&mut st, "1;3;4;9;53");
+    assert!(st.bold && st.italic && st.underline && st.strike && st.overline);
+    apply_sgr(&mut st, "22;23;4:2;29;55");
+    assert!(!st.bold && !st.italic && !st.strike && !st.overline);
+    assert!(st.dunderline && !st.underline);
+    apply_sgr(&mut st, "24;2;7");
+    assert!(!st.dunderline && st.faint && st.reverse);
+    apply_sgr(&mut st, "0");
+    assert_eq!(st, Style::default());
+
+    // reverse video swaps foreground and background
+    let st = Style {
+        reverse: true,
+        fg: Color::Index(1),
+        ..Default::default()
+    };
+    assert_eq!(fg_cmd(&st).unwrap(), "\\color{fwbg}");
+    assert_eq!(bg_cmd(&st).unwrap(), "\\color{fwc1}");
+    assert_eq!(fg_cmd(&Style::default()), None);
+    assert_eq!(bg_cmd(&Style::default()), None);
+}
+
+#[test]
+fn test_escape_zero_width() {
+    let param = Param {
+        wmax: 8,
+        ..Default::default()
+    };
+    // colour changes keep the columns and do not wrap
+    let rows = parse_line(
+        "\x1b[31mab\x1b[0m\tc\x1b[1mdefg\x1b[0m",
+        &mut Style::default(),
+        &param,
+    );
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].width, 8);
+    assert_eq!(rows[1].width, 5);
+    assert!(!rows[1].setret);
+}
+
+#[test]
+fn test_split_escapes() {
+    let segs = split_escapes("\x1b[01;31m\x1b[Kfoo\x1b[m\x1b[K:1", false);
+    assert_eq!(
+        segs,
+        vec![
+            ("\x1b[01;31m", true),
+            ("\x1b[K", true),
+            ("foo", false),
+            ("\x1b[m", true),
+            ("\x1b[K", true),
+            (":1", false)
+        ]
+    );
+    // OSC terminated by BEL or ST, charset designation, DCS
+    assert_eq!(scan_escape("\x1b]0;title\x07x"), 10);
+    assert_eq!(scan_escape("\x1b]0;title\x1b\\x"), 11);
+    assert_eq!(scan_escape("\x1b(Bx"), 3);
+    assert_eq!(scan_escape("\x1b#6x"), 3);
+    assert_eq!(scan_escape("\x1bPq#0\x1b\\"), 7);
+    assert_eq!(scan_escape("\x1b[?25lx"), 6);
+    // malformed CSI ends before the offending character
+    assert_eq!(scan_escape("\x1b[3\tx"), 3);
+    assert_eq!(scan_escape("\x1b"), 1);
+
+    assert!(is_known_escape("\x1b[1;38:5:208m"));
+    assert!(!is_known_escape("\x1b[>4;2m"));
+    assert!(!is_known_escape("\x1b[2J"));
+
+    // erase-line no longer swallows the rest of the line
+    let rows = parse_line(
+        "a\x1b[Kbc\x1b[2Jd",
+        &mut Style::default(),
+        &Param::default(),
+    );
+    assert_eq!(rows[0].width, 4);
+
+    // C1 controls are escapes only when they are asked for
+    assert_eq!(
+        split_escapes("a\u{9b}1mb", false),
+        vec![("a\u{9b}1mb", false)]
+    );
+    assert_eq!(split_escapes("a\u{9b}1mb", true)[1], ("\u{9b}1m", true));
+    let mut param = Param {
+        ctrl: CtrlMode::Caret,
+        ..Default::default()
+    };
+    let rows = parse_line("a\u{9b}1mb", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 1 + 4 + 3);
+    param.ansi = true;
+    let rows = parse_line("a\u{9b}1mb", &mut Style::default(), &param);
+    assert_eq!(rows[0].width, 2);
+}
+
+#[test]
+fn test_vt_screen() {
+    let param = Param::default();
+    let mut screen = vt::Screen::new(6, 3);
+    screen.feed("junk\x1b[2J\x1b[Habc\n\x1b[3;2Hxyz\x1b[1;2H\x1b[K", &param);
+    assert_eq!(row_texts(&screen.rows(&param)), vec!["a", "", " xyz"]);
+
+    // pending wrap, scrolling, and a wide character at the margin
+    let mut screen = vt::Screen::new(4, 2);
+    screen.feed("abcdefg\u{65e5}", &param);
+    assert_eq!(row_texts(&screen.rows(&param)), vec!["efg", "\u{65e5}"]);
+
+    // alternate screen is discarded on exit
+    let mut screen = vt::Screen::new(4, 2);
+    screen.feed("ab\x1b[?1049hzz\x1b[?1049lc", &param);
+    assert_eq!(row_texts(&screen.rows(&param)), vec!["abc", ""]);
+    assert!(screen.unknown_escapes().is_empty());
+
+    // ignored sequences are kept for --escwarn
+    let mut screen = vt::Screen::new(4, 2);
+    screen.feed("\x1b[?25la\x1b]0;t\x07\x1b[?25l", &param);
+    assert_eq!(screen.unknown_escapes().get("\x1b[?25l"), Some(&2));
+    assert_eq!(screen.unknown_escapes().len(), 2);
+
+    // erasing or moving a half of a wide character blanks the other
+    for seq in [
+        "\x1b[1;3H\x1b[X",
+        "\x1b[1;3H\x1b[1K",
+        "\x1b[1;2H\x1b[K",
+        "\x1b[1;3H\x1b[@",
+    ] {
+        let mut screen = vt::Screen::new(4, 1);
+        screen.feed(&format!("a\u{65e5}b{}", seq), &param);
+        let rows = screen.rows(&param);
+        assert!(rows[0].width <= 4, "{:?}", seq);
+        assert!(!row_texts(&rows)[0].contains('\u{65e5}'), "{:?}", seq);
+    }
+    let mut screen = vt::Screen::new(4, 1);
+    screen.feed("ab\u{65e5}\x1b[1;2H\x1b[@", &param);
+    assert_eq!(row_texts(&screen.rows(&param)), vec!["a b"]);
+
+    // huge parameters neither overflow nor move out of the screen
+    let mut screen = vt::Screen::new(4, 2);
+    let max = "18446744073709551615";
+    for fin in ["C", "B", "e", "a", "E", "X", "@", "P", "b", "L", "S", "H"] {
+        screen.feed(
+            &format!("a\x1b[{}{}b\x1b[{};{}H", max, fin, max, max),
+            &param,
+        );
+    }
+    screen.feed("\x1b[99999999999999999999999C", &param);
+    assert_eq!(screen.rows(&param).len(), 2);
+
+    // REP measures the character as the input does
+    let wide = Param {
+        ambiguous: AmbiWidth::Wide,
+        ..Default::default()
+    };
+    let mut screen = vt::Screen::new(4, 1);
+    screen.feed("\u{25cb}\x1b[b", &wide);
+    assert_eq!(screen.rows(&wide)[0].width, 4);
+    assert_eq!(parse_vtsize("80x24").unwrap(), (80, 24));
+    assert!(parse_vtsize("80").is_err());
+}
+
+#[test]
+fn test_hyperlink() {
+    let seq = "\x1b]8;id=1;https://example.com/a#b\x1b\\";
+    assert_eq!(osc8_uri(seq), Some("https://example.com/a#b"));
+    assert_eq!(osc8_uri("\x1b]8;;\x07"), Some(""));
+    assert_eq!(osc8_uri("\x1b]0;title\x07"), None);
+    assert!(is_known_escape(seq));
+    assert_eq!(tex_uri("a#b%20{c}"), "a\\#b\\%20\\%7Bc\\%7D");
+
+    // SGR reset keeps the link; OSC 8 with empty URI closes it
+    let param = Param {
+        hyperlink: true,
+        ansi: true,
+        ..Default::default()
+    };
+    let rows = parse_line(
+        "x\x1b]8;;file:///tmp\x07\x1b[1mab\x1b[0mc\x1b]8;;\x07d",
+        &mut Style::default(),
+        &param,
+    );
+    let links: Vec<Option<&str>> = rows[0]
+        .tokens
+        .iter()
+        .filter(|tk| tk.width > 0)
+        .map(|tk| tk.style.link.as_deref())
+        .collect();
+    let tmp = Some("file:///tmp");
+    assert_eq!(links, vec![None, tmp, tmp, tmp, None]);
+
+    // a link of the screen is dropped without --hyperlink
+    let mut param = Param {
+        ansi: true,
+        ..Default::default()
+    };
+    let mut screen = vt::Screen::new(4, 1);
+    screen.feed("\x1b]8;;file:///tmp\x07\x1b[1mab", &param);
+    let rows = screen.rows(&param);
+    assert!(rows[0].tokens.iter().all(|tk| tk.style.link.is_none()));
+    assert!(rows[0].tokens[0].style.bold);
+    param.hyperlink = true;
+    let rows = screen.rows(&param);
+    assert_eq!(rows[0].tokens[0].style.link.as_deref(), tmp);
+}
+
+#[test]
+fn test_autolink() {
+    let links = find_links("see (https://example.com/a_(b)), mailto:x@y.org. a / b");
+    let uris: Vec<&str> = links.iter().map(|(_, _, u)| u.as_str()).collect();
+    assert_eq!(uris, vec!["https://example.com/a_(b)", "mailto:x@y.org"]);
+    assert_eq!((links[0].0, links[0].1), (5, 30));
+    assert!(find_links("mailto: http://. ~/a").is_empty());
+    let links = find_links("in /usr/lib/x.so: and a/b/c");
+    assert_eq!(links[0].2, "file:///usr/lib/x.so");
+    assert_eq!(links.len(), 1);
+    assert!(find_links("<p>x</p></div> a<b/c").is_empty());
+    assert_eq!(find_links("(/tmp) \"/etc\"").len(), 2);
+
+    let mut param = Param {
+        autolink: true,
+        hyperlink: true,
+        ..Default::default()
+    };
+    let rows = parse_line("日本 http://a.jp/ 語", &mut Style::default(), &param);
+    let linked = rows[0]
+        .tokens
+        .iter()
+        .filter(|tk| tk.style.link.as_deref() == Some("http://a.jp/"))
+        .count();
+    assert_eq!(linked, "http://a.jp/".len());
+
+    // a link wrapped over rows is found as a whole
+    param.wmax = 8;
+    let rows = parse_line("x https://a.jp/b", &mut Style::default(), &param);
+    assert_eq!(rows.len(), 2);
+    assert!(
+        rows[1]
+            .tokens
+            .iter()
+            .all(|tk| tk.style.link.as_deref() == Some("https://a.jp/b"))
+    );
+}
+
+#[test]
+fn test_parse_theme() {
+    assert_eq!(parse_theme("xterm").unwrap(), Theme::default());
+    let dark = parse_theme("solarized-dark").unwrap();
+    assert_eq!(dark.bg, (0, 43, 54));
+
+    let mut theme = Theme::default();
+    parse_theme_entry("color1 #FF8000", &mut theme).unwrap();
+    parse_theme_entry("background: #1e1e1e", &mut theme).unwrap();
+    assert_eq!(theme.palette[1], (255, 128, 0));
+    assert_eq!(theme.bg, (30, 30, 30));
+    assert!(parse_theme_entry("color16 #000000", &mut theme).is_err());
+    assert!(parse_theme_entry("cursor #000000", &mut theme).is_err());
+    assert!(parse_theme_entry("color0 #00000", &mut theme).is_err());
+    assert!(parse_rgb("#+12345").is_err());
+    assert!(parse_rgb("#-12345").is_err());
+    assert_eq!(parse_rgb("#aBc123").unwrap(), (0xab, 0xc1, 0x23));
+    assert!(parse_theme("no-such-theme").is_err());
+}
+
+#[test]
+fn test_mono_style() {
+    assert_eq!(color_class(&Color::Index(9)), Some(1));
+    assert_eq!(color_class(&Color::Index(46)), Some(2));
+    assert_eq!(color_class(&Color::Rgb(40, 40, 200)), Some(4));
+    assert_eq!(color_class(&Color::Default), None);
+
+    let mut st = Style::default();
+    apply_sgr(&mut st, "31;42");
+    let mono = mono_style(&st);
+    assert!(mono.bold && !mono.italic && !mono.underline);
+    assert_eq!((mono.fg, mono.bg), (Color::Default, Color::Index(2)));
+
+    // reverse video of default colours becomes a dark background
+    let mut st = Style::default();
+    apply_sgr(&mut st, "7");
+    let mono = mono_style(&st);
+    assert!(!mono.reverse);
+    assert_eq!(mono.bg, Color::Index(0));
+}
+
+#[test]
+fn test_contrast_rgb() {
+    assert_eq!(contrast_rgb((0, 0, 0)), (153, 153, 153));
+    assert_eq!(contrast_rgb((255, 255, 255)), (102, 102, 102));
+    // solarized-dark is dark, solarized-light is light
+    let (r, _, _) = contrast_rgb(parse_theme("solarized-dark").unwrap().bg);
+    assert!(r > 128);
+    let (r, _, _) = contrast_rgb(parse_theme("solarized-light").unwrap().bg);
+    assert!(r < 128);
+}
+
+#[test]
+fn test_box_arms() {
+    assert_eq!(draw::box_arms('─'), Some([1, 0, 1, 0]));
+    assert_eq!(draw::box_arms('┢'), Some([0, 1, 2, 2]));
+    assert_eq!(draw::box_arms('╔'), Some([0, 0, 3, 3]));
+    assert_eq!(draw::box_arms('╪'), Some([3, 1, 3, 1]));
+    assert_eq!(draw::box_arms('╿'), Some([0, 2, 0, 1]));
+    assert_eq!(draw::box_arms('▀'), None);
+    assert_eq!(draw::box_arms('a'), None);
+}
+
+#[test]
+fn test_block_rects() {
+    assert_eq!(draw::block_rects('▂'), Some(vec![(0, 0, 8, 2)]));
+    assert_eq!(draw::block_rects('█'), Some(vec![(0, 0, 8, 8)]));
+    assert_eq!(draw::block_rects('▊'), Some(vec![(0, 0, 6, 8)]));
+    assert_eq!(
+        draw::block_rects('▚'),
+        Some(vec![(0, 4, 4, 8), (4, 0, 8, 4)])
+    );
+    assert_eq!(draw::block_rects('▒'), Some(vec![]));
+    assert_eq!(draw::block_rects('─'), None);
+}
+
+#[test]
+fn test_vt_linesize() {
+    let param = Param::default();
+    let mut screen = vt::Screen::new(8, 4);
+    screen.feed("\x1b#6abcdefg\r\n\x1b#3xy\r\n\x1b#4xy", &param);
+    let rows = screen.rows(&param);
+    // half the columns are left; the text wraps at the fourth
+    assert_eq!(rows[0].size, LineSize::Wide);
+    assert_eq!(rows[0].width, 8);
+    assert_eq!((rows[1].size, rows[1].width), (LineSize::Single, 3));
+    assert_eq!((rows[2].size, rows[2].width), (LineSize::Top, 4));
+    assert_eq!(rows[3].size, LineSize::Bottom);
+
+    // line attributes scroll with the lines
+    screen.feed("\r\n", &param);
+    assert_eq!(screen.rows(&param)[1].size, LineSize::Top);
+}
+
+#[test]
+fn test_double_height() {
+    let mut param = Param {
+        lmax: 4,
+        ffnewpage: true,
+        ..Default::default()
+    };
+    let mut screen = vt::Screen::new(8, 4);
+    screen.feed("a\r\nb\r\n\x1b#3xy\r\n\x1b#4xy", &param);
+    let mut rows = screen.rows(&param);
+
+    // a double-height pair is not split over pictures, with no page break
+    assert!(splits_pair(&rows[2..], 3, 4));
+    assert!(!splits_pair(&rows[2..], 2, 4));
+    assert!(!splits_pair(&rows[1..], 3, 4));
+    assert_eq!(picture_break(&rows[2..], 3, &param), Some(false));
+    assert_eq!(picture_break(&rows[2..], 2, &param), None);
+    assert_eq!(picture_break(&rows[2..], 0, &param), None);
+    rows[2].ffbreak = true;
+    assert_eq!(picture_break(&rows[2..], 2, &param), Some(true));
+    param.pagebreaking = true;
+    assert_eq!(picture_break(&rows[2..], 2, &param), Some(false));
+
+    // the top half is drawn over the bottom row, unless it is alone
+    assert_eq!(drawn_size(&rows, 2), LineSize::Top);
+    assert_eq!(drawn_size(&rows, 3), LineSize::Bottom);
+    assert_eq!(drawn_size(&rows[..3], 2), LineSize::Wide);
+    let top = scaled_cmd(10, 20, 2, "x", LineSize::Top, &param);
+    assert!(top.starts_with(" \\put(10,8)") && top.contains("\\scalebox{2}{"));
+    let lone = scaled_cmd(10, 20, 2, "x", drawn_size(&rows[..3], 2), &param);
+    assert_eq!(
+        lone,
+        " \\put(10,20){\\makebox(10,10){\\VV\\scalebox{2}[1]{\\mbox{x}}}}"
+    );
+}
+
+#[test]
+fn test_wrap_word() {
+    let param = Param {
+        wmax: 10,
+        wrap: WrapMode::Word,
+        ..Default::default()
+    };
+    let rows = parse_line(
+        "make install-strip DESTDIR=/tmp",
+        &mut Style::default(),
+        &param,
+    );
+    assert_eq!(
+        row_texts(&rows),
+        vec!["make", "install-", "strip", "DESTDIR=/", "tmp"]
+    );
+    assert!(rows[0].setret && !rows[4].setret);
+
+    // a word over wmax breaks hard
+    let rows = parse_line("a abcdefghijklm", &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows), vec!["a abcdefgh", "ijklm"]);
+
+    // spaces at the break are dropped
+    let rows = parse_line("abcdefghij  klm", &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows), vec!["abcdefghij", "klm"]);
+    let rows = parse_line("abcdefgh ijk", &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows), vec!["abcdefgh", "ijk"]);
+    assert_eq!(rows[0].width, 8);
+}
+
+#[test]
+fn test_kinsoku() {
+    let mut param = Param {
+        wmax: 8,
+        ..Default::default()
+    };
+    let line = "これは「例」です。次";
+    assert_eq!(
+        row_texts(&parse_line(line, &mut Style::default(), &param)),
+        vec!["これは「", "例」です", "。次"]
+    );
+
+    // 「 moves down; 。 pulls す down
+    param.kinsoku = true;
+    assert_eq!(
+        row_texts(&parse_line(line, &mut Style::default(), &param)),
+        vec!["これは", "「例」で", "す。次"]
+    );
+
+    // 。 hangs in the margin
+    param.hanging = true;
+    let rows = parse_line(line, &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows), vec!["これは", "「例」で", "す。次"]);
+    let rows = parse_line("あいうえ。お", &mut Style::default(), &param);
+    assert_eq!(row_texts(&rows), vec!["あいうえ。", "お"]);
+    // the canvas is as wide as the longest row, with the hung cell
+    assert_eq!(rows[0].width, 8 + 2);
+    assert_eq!(rows[1].width, 2);
+}
@@ -0,0 +1,550 @@
+//
+// vt --- VT100/xterm screen model for captures of full-screen programs.
+//
+// the byte stream is played on a screen of a given size, and the final
+// screen becomes rows of fwtype.  LF implies CR, as a tty with onlcr.
+//
+
+use crate::{
+    LineSize, Param, Row, RowChunk, Style, Token, TokenKind, apply_sgr, grapheme_width, osc8_uri,
+    scan_escape, split_escapes,
+};
+use std::collections::BTreeMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug, Clone)]
+struct Cell {
+    text: String,
+    width: isize, /* 0 for the right half of a wide character */
+    style: Style,
+}
+
+impl Cell {
+    /// erased cell; it keeps the current background only.
+    fn blank(style: &Style) -> Cell {
+        Cell {
+            text: " ".to_string(),
+            width: 1,
+            style: Style {
+                bg: style.bg,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// DEC special graphics for 0x5f..0x7e.
+const DEC_GRAPHICS: &str = " ◆▒␉␌␍␊°±␤␋┘┐┌└┼⎺⎻─⎼⎽├┤┴┬│≤≥π≠£·";
+
+#[derive(Debug)]
+pub struct Screen {
+    cols: usize,
+    rows: usize,
+    grid: Vec<Vec<Cell>>,
+    sizes: Vec<LineSize>,
+    maingrid: Option<(Vec<Vec<Cell>>, Vec<LineSize>)>,
+    x: usize,
+    y: usize,
+    wrapnext: bool,
+    autowrap: bool,
+    style: Style,
+    saved: (usize, usize, Style),
+    top: usize,
+    bottom: usize,
+    graphics: [bool; 2],
+    shifted: bool,
+    unknown: BTreeMap<String, usize>,
+}
+
+impl Screen {
+    pub fn new(cols: usize, rows: usize) -> Screen {
+        let style = Style::default();
+        Screen {
+            cols,
+            rows,
+            grid: vec![vec![Cell::blank(&style); cols]; rows],
+            sizes: vec![LineSize::Single; rows],
+            maingrid: None,
+            x: 0,
+            y: 0,
+            wrapnext: false,
+            autowrap: true,
+            style: style.clone(),
+            saved: (0, 0, style),
+            top: 0,
+            bottom: rows - 1,
+            graphics: [false, false],
+            shifted: false,
+            unknown: BTreeMap::new(),
+        }
+    }
+
+    pub fn feed(&mut self, s: &str, param: &Param) {
+        for (seg, is_escape) in split_escapes(s, true) {
+            if is_escape {
+                if !self.escape(seg, param) {
+                    *self.unknown.entry(seg.to_string()).or_insert(0) += 1;
+                }
+                continue;
+            }
+            for g in seg.graphemes(true) {
+                match g {
+                    "\n" | "\x0b" | "\x0c" | "\r\n" => {
+                        self.x = 0;
+                        self.linefeed();
+                    }
+                    "\r" => {
+                        self.x = 0;
+                        self.wrapnext = false;
+                    }
+                    "\x08" => {
+                        self.x = self.x.saturating_sub(1);
+                        self.wrapnext = false;
+                    }
+                    "\t" => {
+                        let ts = param.tabstop;
+                        self.x = ((self.x / ts + 1) * ts).min(self.width() - 1);
+                        self.wrapnext = false;
+                    }
+                    "\x0e" => self.shifted = true,
+                    "\x0f" => self.shifted = false,
+                    _ if g.chars().all(char::is_control) => {}
+                    _ => self.put(g, param),
+                }
+            }
+        }
+    }
+
+    fn put(&mut self, g: &str, param: &Param) {
+        let mut text = g.to_string();
+        if self.graphics[self.shifted as usize]
+            && let Some(c) = g.chars().next()
+            && ('\x5f'..='\x7e').contains(&c)
+        {
+            let i = c as usize - 0x5f;
+            text = DEC_GRAPHICS.chars().nth(i).unwrap_or(c).to_string();
+        }
+        let w = grapheme_width(&text, param).min(2);
+        if w == 0 {
+            /* combining; joins to the previous cell */
+            let px = self.prev_cell();
+            self.grid[self.y][px].text.push_str(&text);
+            return;
+        }
+
+        if self.wrapnext || (w == 2 && self.x + 1 >= self.width()) {
+            if self.autowrap {
+                self.x = 0;
+                self.linefeed();
+            } else if w == 2 {
+                self.x = self.width().saturating_sub(2);
+            }
+        }
+        self.wrapnext = false;
+        if w as usize > self.width() {
+            return;
+        }
+
+        self.clear_wide(self.x, self.y);
+        if w == 2 {
+            self.clear_wide(self.x + 1, self.y);
+        }
+        self.grid[self.y][self.x] = Cell {
+            text,
+            width: w,
+            style: self.style.clone(),
+        };
+        if w == 2 {
+            self.grid[self.y][self.x + 1] = Cell {
+                text: String::new(),
+                width: 0,
+                style: self.style.clone(),
+            };
+        }
+
+        self.x += w as usize;
+        if self.x >= self.width() {
+            self.x = self.width() - 1;
+            self.wrapnext = self.autowrap;
+        }
+    }
+
+    /// column of the character just put, or before the cursor.
+    fn prev_cell(&self) -> usize {
+        let mut px = if self.wrapnext {
+            self.x
+        } else {
+            self.x.saturating_sub(1)
+        };
+        while px > 0 && self.grid[self.y][px].width == 0 {
+            px -= 1;
+        }
+        px
+    }
+
+    /// break a wide character of which a half is to be overwritten,
+    /// erased or moved; both halves become blanks.
+    fn clear_wide(&mut self, x: usize, y: usize) {
+        let row = &mut self.grid[y];
+        let x0 = match row[x].width {
+            0 if x > 0 => x - 1,
+            2 => x,
+            _ => return,
+        };
+        for cell in row.iter_mut().skip(x0).take(2) {
+            *cell = Cell {
+                style: cell.style.clone(),
+                ..Cell::blank(&Style::default())
+            };
+        }
+    }
+
+    fn linefeed(&mut self) {
+        self.wrapnext = false;
+        if self.y == self.bottom {
+            self.scroll_up(1);
+        } else if self.y + 1 < self.rows {
+            self.y += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.wrapnext = false;
+        if self.y == self.top {
+            self.scroll_down(1);
+        } else if self.y > 0 {
+            self.y -= 1;
+        }
+    }
+
+    /// columns of the cursor row; a half in double-width lines.
+    fn width(&self) -> usize {
+        match self.sizes[self.y] {
+            LineSize::Single => self.cols,
+            _ => (self.cols / 2).max(1),
+        }
+    }
+
+    /// DEC line attribute of the cursor row; the right half of the
+    /// line is lost by doubling.
+    fn set_linesize(&mut self, size: LineSize) {
+        self.sizes[self.y] = size;
+        let w = self.width();
+        self.erase(self.y, w, self.cols);
+        self.goto(self.x, self.y);
+    }
+
+    fn blank_row(&self) -> Vec<Cell> {
+        vec![Cell::blank(&self.style); self.cols]
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        for _ in 0..n.min(self.bottom - self.top + 1) {
+            self.grid.remove(self.top);
+            self.sizes.remove(self.top);
+            let row = self.blank_row();
+            self.grid.insert(self.bottom, row);
+            self.sizes.insert(self.bottom, LineSize::Single);
+        }
+    }
+
+    fn scroll_down(&mut self, n: usize) {
+        for _ in 0..n.min(self.bottom - self.top + 1) {
+            self.grid.remove(self.bottom);
+            self.sizes.remove(self.bottom);
+            let row = self.blank_row();
+            self.grid.insert(self.top, row);
+            self.sizes.insert(self.top, LineSize::Single);
+        }
+    }
+
+    /// erase cells x0..x1; a wide character across either end is
+    /// erased as a whole.
+    fn erase(&mut self, y: usize, x0: usize, x1: usize) {
+        let x1 = x1.min(self.cols);
+        if x0 < x1 {
+            self.clear_wide(x0, y);
+            self.clear_wide(x1 - 1, y);
+        }
+        let blank = Cell::blank(&self.style);
+        for x in x0..x1 {
+            self.grid[y][x] = blank.clone();
+        }
+    }
+
+    fn goto(&mut self, x: usize, y: usize) {
+        self.y = y.min(self.rows - 1);
+        self.x = x.min(self.width() - 1);
+        self.wrapnext = false;
+    }
+
+    /// sequences the screen does not know of are ignored, and false
+    /// is returned for --escwarn.
+    fn escape(&mut self, seq: &str, param: &Param) -> bool {
+        let body = match seq.strip_prefix("\x1b[") {
+            Some(b) => b,
+            None => match seq.strip_prefix('\u{9b}') {
+                Some(b) => b,
+                None => return self.esc(seq),
+            },
+        };
+        /* CSI; private marker, parameters, intermediates and final */
+        let Some(fin) = body.chars().last() else {
+            return false;
+        };
+        if scan_escape(seq) != seq.len() || !('\x40'..='\x7e').contains(&fin) {
+            return false;
+        }
+        let body = &body[..body.len() - 1];
+        let private = body.chars().next().filter(|c| "<=>?".contains(*c));
+        let pstr = body.trim_start_matches(['<', '=', '>', '?']);
+        let pstr = pstr.trim_end_matches(|c: char| ('\x20'..='\x2f').contains(&c));
+        /* parameters are clamped as xterm does; no overflow in moves */
+        let ps: Vec<usize> = pstr
+            .split(';')
+            .map(|v| v.split(':').next().unwrap_or("").parse().unwrap_or(0))
+            .map(|v: usize| v.min(u16::MAX as usize))
+            .collect();
+        let arg = |i: usize, def: usize| match ps.get(i) {
+            Some(&0) | None => def,
+            Some(&v) => v,
+        };
+        let n = arg(0, 1);
+
+        match (private, fin) {
+            (None, 'm') => apply_sgr(&mut self.style, pstr),
+            (None, '@') => {
+                let (x, y) = (self.x, self.y);
+                self.clear_wide(x, y);
+                for _ in 0..n.min(self.cols - x) {
+                    self.grid[y].pop();
+                    let blank = Cell::blank(&self.style);
+                    self.grid[y].insert(x, blank);
+                }
+                /* a wide character pushed half out of the line */
+                self.clear_wide(self.cols - 1, y);
+            }
+            (None, 'A') => self.goto(self.x, self.y.saturating_sub(n).max(self.top.min(self.y))),
+            (None, 'B' | 'e') => self.goto(self.x, (self.y + n).min(self.bottom.max(self.y))),
+            (None, 'C' | 'a') => self.goto(self.x + n, self.y),
+            (None, 'D') => self.goto(self.x.saturating_sub(n), self.y),
+            (None, 'E') => self.goto(0, self.y + n),
+            (None, 'F') => self.goto(0, self.y.saturating_sub(n)),
+            (None, 'G' | '`') => self.goto(n - 1, self.y),
+            (None, 'H' | 'f') => self.goto(arg(1, 1) - 1, n - 1),
+            (None, 'd') => self.goto(self.x, n - 1),
+            (None, 'J') => {
+                let (x, y) = (self.x, self.y);
+                let (y0, y1) = match ps[0] {
+                    0 => {
+                        self.erase(y, x, self.cols);
+                        (y + 1, self.rows)
+                    }
+                    1 => {
+                        self.erase(y, 0, x + 1);
+                        (0, y)
+                    }
+                    _ => (0, self.rows),
+                };
+                for yy in y0..y1 {
+                    self.erase(yy, 0, self.cols);
+                    self.sizes[yy] = LineSize::Single;
+                }
+            }
+            (None, 'K') => {
+                let (x, y) = (self.x, self.y);
+                match ps[0] {
+                    0 => self.erase(y, x, self.cols),
+                    1 => self.erase(y, 0, x + 1),
+                    _ => self.erase(y, 0, self.cols),
+                }
+            }
+            (None, 'X') => self.erase(self.y, self.x, self.x + n),
+            (None, 'P') => {
+                let (x, y) = (self.x, self.y);
+                self.clear_wide(x, y);
+                self.clear_wide((x + n).min(self.cols) - 1, y);
+                for _ in 0..n.min(self.cols - x) {
+                    self.grid[y].remove(x);
+                    let blank = Cell::blank(&self.style);
+                    self.grid[y].push(blank);
+                }
+            }
+            (None, 'L' | 'M') if (self.top..=self.bottom).contains(&self.y) => {
+                let (top, y) = (self.top, self.y);
+                self.top = y;
+                if fin == 'L' {
+                    self.scroll_down(n);
+                } else {
+                    self.scroll_up(n);
+                }
+                self.top = top;
+                self.x = 0;
+            }
+            (None, 'L' | 'M') => {}
+            (None, 'S') => self.scroll_up(n),
+            (None, 'T') => self.scroll_down(n),
+            (None, 'b') => {
+                let px = self.prev_cell();
+                let last = self.grid[self.y][px].text.clone();
+                let style = self.style.clone();
+                self.style = self.grid[self.y][px].style.clone();
+                for _ in 0..n.min(self.cols * self.rows) {
+                    self.put_raw(&last, param);
+                }
+                self.style = style;
+            }
+            (None, 'r') => {
+                let top = arg(0, 1) - 1;
+                let bottom = arg(1, self.rows).min(self.rows) - 1;
+                if top < bottom {
+                    self.top = top;
+                    self.bottom = bottom;
+                    self.goto(0, 0);
+                }
+            }
+            (None, 's') => self.saved = (self.x, self.y, self.style.clone()),
+            (None, 'u') => self.restore(),
+            (Some('?'), 'h' | 'l') if ps.iter().all(|p| matches!(p, 7 | 47 | 1047 | 1049)) => {
+                let set = fin == 'h';
+                for p in &ps {
+                    match p {
+                        7 => self.autowrap = set,
+                        _ => self.altscreen(set, *p == 1049),
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// put a cell text as is, without the charset translation.
+    fn put_raw(&mut self, text: &str, param: &Param) {
+        let graphics = self.graphics;
+        self.graphics = [false, false];
+        self.put(text, param);
+        self.graphics = graphics;
+    }
+
+    fn restore(&mut self) {
+        let (x, y, style) = self.saved.clone();
+        self.style = style;
+        self.goto(x, y);
+    }
+
+    fn altscreen(&mut self, set: bool, cursor: bool) {
+        if set && self.maingrid.is_none() {
+            if cursor {
+                self.saved = (self.x, self.y, self.style.clone());
+            }
+            let alt = vec![self.blank_row(); self.rows];
+            let grid = std::mem::replace(&mut self.grid, alt);
+            let sizes = std::mem::replace(&mut self.sizes, vec![LineSize::Single; self.rows]);
+            self.maingrid = Some((grid, sizes));
+        } else if !set && let Some((grid, sizes)) = self.maingrid.take() {
+            self.grid = grid;
+            self.sizes = sizes;
+            if cursor {
+                self.restore();
+            }
+        }
+    }
+
+    /// escape sequences other than CSI; control strings but OSC 8
+    /// are ignored.
+    fn esc(&mut self, seq: &str) -> bool {
+        if let Some(uri) = osc8_uri(seq) {
+            self.style.link = Some(uri.to_string()).filter(|u| !u.is_empty());
+            return true;
+        }
+        let Some(body) = seq.strip_prefix('\x1b') else {
+            return false;
+        };
+        match body {
+            "7" => self.saved = (self.x, self.y, self.style.clone()),
+            "8" => self.restore(),
+            "D" => self.linefeed(),
+            "E" => {
+                self.x = 0;
+                self.linefeed();
+            }
+            "M" => self.reverse_index(),
+            "c" => {
+                let unknown = std::mem::take(&mut self.unknown);
+                *self = Screen::new(self.cols, self.rows);
+                self.unknown = unknown;
+            }
+            "(0" => self.graphics[0] = true,
+            ")0" => self.graphics[1] = true,
+            "(B" | "(A" | "(1" | "(2" => self.graphics[0] = false,
+            ")B" | ")A" | ")1" | ")2" => self.graphics[1] = false,
+            "#3" => self.set_linesize(LineSize::Top),
+            "#4" => self.set_linesize(LineSize::Bottom),
+            "#5" => self.set_linesize(LineSize::Single),
+            "#6" => self.set_linesize(LineSize::Wide),
+            "#8" => {
+                for row in self.grid.iter_mut() {
+                    for cell in row.iter_mut() {
+                        cell.text = "E".to_string();
+                        cell.width = 1;
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// escape sequences ignored by the screen, and their counts.
+    pub fn unknown_escapes(&self) -> &BTreeMap<String, usize> {
+        &self.unknown
+    }
+
+    /// rows of the final screen; trailing blanks are trimmed.
+    pub fn rows(&self, param: &Param) -> RowChunk {
+        let mut rchk: RowChunk = Vec::new();
+        for (i, (line, &size)) in self.grid.iter().zip(&self.sizes).enumerate() {
+            let ncols = match size {
+                LineSize::Single => self.cols,
+                _ => (self.cols / 2).max(1),
+            };
+            let mut tokens: Vec<Token> = Vec::new();
+            for cell in &line[..ncols] {
+                if cell.width == 0 {
+                    continue;
+                }
+                let kind = if cell.text.len() == 1 && cell.text.is_ascii() {
+                    TokenKind::Ascii(cell.text.clone())
+                } else {
+                    TokenKind::Misc(cell.text.clone())
+                };
+                let mut tk = Token::new(kind, cell.width);
+                if param.ansi {
+                    tk.style = cell.style.clone();
+                }
+                tk.style.link = cell.style.link.clone().filter(|_| param.hyperlink);
+                tokens.push(tk);
+            }
+            while let Some(tk) = tokens.last() {
+                match &tk.kind {
+                    TokenKind::Ascii(s) if s == " " && tk.style == Style::default() => {
+                        tokens.pop();
+                    }
+                    _ => break,
+                }
+            }
+            let mut row = Row {
+                lineno: i as isize + 1,
+                width: -1,
+                setret: false,
+                ffbreak: false,
+                size,
+                tokens,
+            };
+            row.calcwidth();
+            rchk.push(row);
+        }
+        rchk
+    }
+}